@@ -0,0 +1,70 @@
+//! Compares how many allocations `read_packets` and `read_packets_iter` perform, and how long they take.
+//! Run with `cargo bench`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use libuio::socket::{Packet, StreamChannel, StreamSocket};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const PACKETS_PER_ROUND: usize = 64;
+const ROUNDS: usize = 1000;
+
+fn write_round(sender: &mut StreamChannel) {
+    let packets = (0 .. PACKETS_PER_ROUND)
+        .map(|_| Packet::new(vec![0; 32], Vec::new()))
+        .collect();
+    sender.write_packets(packets).unwrap();
+}
+
+/// Returns the average amount of allocations and the average time per packet spent by `read`.
+fn measure(sender: &mut StreamChannel, receiver: &mut StreamChannel, read: impl Fn(&mut StreamChannel) -> usize) -> (f64, Duration) {
+    let mut allocations = 0;
+    let mut elapsed = Duration::ZERO;
+    for _ in 0 .. ROUNDS {
+        write_round(sender);
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let num_packets = read(receiver);
+        elapsed += start.elapsed();
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert_eq!(num_packets, PACKETS_PER_ROUND);
+    }
+    let num_packets = ROUNDS * PACKETS_PER_ROUND;
+    (allocations as f64 / num_packets as f64, elapsed / num_packets as u32)
+}
+
+fn main() {
+    let path = std::env::temp_dir().join(format!("libuio-bench-{}.socket", std::process::id()));
+    let socket = StreamSocket::open(path.clone()).unwrap();
+    let mut sender = StreamChannel::open(&path).unwrap();
+    let (mut receiver, _credentials) = socket.accept().unwrap();
+
+    let collected = measure(&mut sender, &mut receiver, |receiver| {
+        receiver.read_packets().unwrap().len()
+    });
+    let iterated = measure(&mut sender, &mut receiver, |receiver| {
+        receiver.read_packets_iter().unwrap().count()
+    });
+
+    println!("read_packets:      {:.3} allocations, {:?} per packet", collected.0, collected.1);
+    println!("read_packets_iter: {:.3} allocations, {:?} per packet", iterated.0, iterated.1);
+}
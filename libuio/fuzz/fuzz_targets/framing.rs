@@ -0,0 +1,30 @@
+//! Feeds arbitrary byte streams to the framing layer. Run with `cargo fuzz run framing` from the libuio
+//! directory.
+//!
+//! The first byte of the input decides how big the chunks are that the rest gets fed in, so that packets get
+//! split over several feeds the way they can get split over several reads. The seed corpus in
+//! `corpus/framing` is framed without the `checksum` feature.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libuio::framing::FrameReader;
+use libuio::socket::MAX_PAYLOAD_LEN;
+
+fuzz_target!(|input: &[u8]| {
+    let Some((&chunk_size, stream)) = input.split_first() else { return };
+    let mut reader = FrameReader::new();
+    let mut drained = 0;
+    for chunk in stream.chunks(usize::from(chunk_size) + 1) {
+        if reader.feed(chunk, Vec::new()).is_err() {
+            return;
+        }
+        for packet in reader.drain() {
+            assert!(packet.len() <= MAX_PAYLOAD_LEN);
+            // Nothing was fed any file descriptors, so only packets without them can be complete.
+            assert_eq!(packet.fd_count(), 0);
+            drained += packet.len();
+        }
+    }
+    assert!(drained + reader.buffered_len() <= stream.len());
+});
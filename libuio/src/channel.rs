@@ -0,0 +1,59 @@
+//! The transport-independent interface through which packets get exchanged, so code that only cares about
+//! the packets can be tested without real sockets.
+
+use std::os::fd::OwnedFd;
+
+use crate::message::{Event, Request};
+use crate::socket::{Message, Packet};
+
+pub trait Channel {
+    /// Returns all packets that have been completely received. Returns an empty Vec if there was nothing to
+    /// read, or an error of kind `ConnectionReset` if the peer has closed its end of the channel.
+    fn read_packets(&mut self) -> Result<Vec<Packet>, std::io::Error>;
+
+    /// Sends a packet, or queues it if it cannot be sent right now.
+    fn write_packet(&mut self, packet: Packet) -> Result<(), std::io::Error>;
+
+    /// How many bytes of written packets have not been sent yet.
+    fn queued_bytes(&self) -> usize;
+
+    /// Whether some written packets have not been sent yet.
+    fn has_pending_writes(&self) -> bool {
+        self.queued_bytes() > 0
+    }
+
+    /// Serializes a request and writes it to this channel. Used by the client.
+    fn send_request(&mut self, request: Request, fds: Vec<OwnedFd>) -> Result<(), std::io::Error> {
+        let packet = Packet::try_from((request, fds))?;
+        self.write_packet(packet)
+    }
+
+    /// Reads all complete packets and deserializes them as requests, in order. Used by the server. A packet
+    /// that cannot be deserialized does not take the others down with it, because they have already been
+    /// removed from the channel.
+    fn recv_requests(&mut self) -> Result<Vec<Result<Message<Request>, std::io::Error>>, std::io::Error> {
+        Ok(self.read_packets()?.into_iter()
+            .map(|packet| {
+                let (msg, fds): (Request, _) = packet.try_into()?;
+                Ok(Message { msg, fds })
+            })
+            .collect())
+    }
+
+    /// Serializes an event and writes it to this channel. Used by the server.
+    fn send_event(&mut self, event: Event, fds: Vec<OwnedFd>) -> Result<(), std::io::Error> {
+        let packet = Packet::try_from((event, fds))?;
+        self.write_packet(packet)
+    }
+
+    /// Reads all complete packets and deserializes them as events, in order. Used by the client. Like with
+    /// `recv_requests`, every packet gets its own result.
+    fn recv_events(&mut self) -> Result<Vec<Result<Message<Event>, std::io::Error>>, std::io::Error> {
+        Ok(self.read_packets()?.into_iter()
+            .map(|packet| {
+                let (msg, fds): (Event, _) = packet.try_into()?;
+                Ok(Message { msg, fds })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,586 @@
+//! Helpers for programs that connect to a UIO server.
+
+use std::collections::VecDeque;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::time::Duration;
+
+use rustix::event::{PollFd, PollFlags};
+
+use crate::channel::Channel;
+use crate::message::{AnnounceMsg, Event, EventMsg, HelloMsg, Request, RequestMsg, SerialCounter};
+use crate::socket::{retry_on_interrupt, server_may_come_up, StreamChannel};
+
+/// Decides how often and how patiently `connect_with_retry` tries to reach the server.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How long to wait after the first failed attempt. The delay doubles after every further attempt.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Gives up after this many attempts. `None` keeps trying forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(1),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Connects to the server at the given path, waiting for it to come up if it is not running yet, for example
+/// because it is being restarted. Returns the last error if the policy runs out of attempts, or immediately
+/// if the error is not one that can go away by waiting.
+pub fn connect_with_retry(path: &Path, policy: &RetryPolicy) -> Result<StreamChannel, std::io::Error> {
+    retry(path, policy, || StreamChannel::open(path))
+}
+
+/// Repeats `attempt` according to the policy for as long as it fails with an error that can go away by waiting.
+fn retry<T>(
+    path: &Path, policy: &RetryPolicy, mut attempt: impl FnMut() -> Result<T, std::io::Error>,
+) -> Result<T, std::io::Error> {
+    let mut delay = policy.initial_delay;
+    let mut attempts = 0;
+    loop {
+        let err = match attempt() {
+            Ok(connected) => return Ok(connected),
+            Err(err) => err,
+        };
+
+        attempts += 1;
+        if !server_may_come_up(&err) || policy.max_attempts.is_some_and(|max| attempts >= max) {
+            return Err(err);
+        }
+
+        log::trace!("Failed to connect to {}, retrying in {delay:?}: {err}", path.display());
+        std::thread::sleep(delay);
+        delay = (delay * 2).min(policy.max_delay);
+    }
+}
+
+/// A connection to a UIO server that takes care of framing, serials, the hello handshake and answering pings.
+///
+/// ```no_run
+/// use libuio::client::UioClient;
+/// use libuio::message::EventMsg;
+///
+/// let mut client = UioClient::connect(&libuio::socket::default_socket_path()).unwrap();
+/// client.announce("Example").unwrap();
+/// loop {
+///     let event = client.recv_event().unwrap();
+///     if matches!(event.msg, EventMsg::Shutdown) {
+///         break;
+///     }
+/// }
+/// ```
+pub struct UioClient {
+    channel: StreamChannel,
+    serials: SerialCounter,
+    /// Events that have been received but not returned by `recv_event` yet.
+    pending_events: VecDeque<Event>,
+    /// The token that the server handed out when it accepted our announcement.
+    session_token: Option<u64>,
+}
+
+impl UioClient {
+    /// Connects to the server and says hello.
+    pub fn connect(path: &Path) -> Result<Self, std::io::Error> {
+        Self::with_channel(StreamChannel::open(path)?)
+    }
+
+    /// Like `connect_with_retry` followed by `with_channel`. A server that is busy gets retried like one that is
+    /// not up yet, with the same backoff, and those attempts count against the policy's `max_attempts` too.
+    pub fn connect_with_retry(path: &Path, policy: &RetryPolicy) -> Result<Self, std::io::Error> {
+        retry(path, policy, || Self::with_channel(StreamChannel::open(path)?))
+    }
+
+    /// Says hello over an already connected channel. Returns an error of kind `Unsupported` if the server speaks
+    /// another version of the protocol, or of kind `ConnectionRefused` if the server has too many clients. Both
+    /// wrap an `AnnounceRejected`, like the refusals of `announce`. Prefer `UioClient::connect_with_retry` over
+    /// reconnecting right away after the latter.
+    pub fn with_channel(channel: StreamChannel) -> Result<Self, std::io::Error> {
+        let mut client = UioClient {
+            channel, serials: SerialCounter::new(), pending_events: VecDeque::new(), session_token: None,
+        };
+        client.send(RequestMsg::Hello(HelloMsg::current()))?;
+
+        loop {
+            let event = client.next_event()?;
+            match event.msg {
+                EventMsg::Hello(_) => return Ok(client),
+                EventMsg::VersionMismatch { server_version } => {
+                    let reason = format!(
+                        "The server speaks protocol version {server_version}, but we speak version {}.",
+                        HelloMsg::current().version,
+                    );
+                    return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, AnnounceRejected { reason }));
+                },
+                EventMsg::ServerBusy => {
+                    let reason = "The server has too many clients.".to_owned();
+                    return Err(std::io::Error::new(std::io::ErrorKind::ConnectionRefused, AnnounceRejected { reason }));
+                },
+                _ => log::warn!("Ignoring an event that was sent before the server said hello: {event:?}"),
+            }
+        }
+    }
+
+    /// Identifies this client to the server and waits for the server to accept it. If the server refuses, an
+    /// error of kind `PermissionDenied` is returned that wraps an `AnnounceRejected`.
+    ///
+    /// Unrelated events that arrive in the meantime are kept for `recv_event`.
+    pub fn announce(&mut self, name: &str) -> Result<(), std::io::Error> {
+        let serial = self.send(RequestMsg::Announce(AnnounceMsg { name: name.to_owned() }))?;
+        self.wait_for_acceptance(serial)
+    }
+
+    /// Takes over the session of an earlier connection instead of announcing, using the token returned by
+    /// `session_token` on that connection. Fails like `announce` if the session has expired.
+    pub fn resume(&mut self, token: u64) -> Result<(), std::io::Error> {
+        let serial = self.send(RequestMsg::Resume { token })?;
+        self.wait_for_acceptance(serial)?;
+        self.session_token = Some(token);
+        Ok(())
+    }
+
+    /// The token with which a later connection can resume this client's session, once the server has accepted
+    /// the announcement.
+    pub fn session_token(&self) -> Option<u64> {
+        self.session_token
+    }
+
+    /// Waits for the reply to the announcement or resumption with the given serial.
+    fn wait_for_acceptance(&mut self, serial: u32) -> Result<(), std::io::Error> {
+        let mut unrelated = Vec::new();
+        let result = loop {
+            let event = self.recv_event()?;
+            match event {
+                Event { serial: Some(s), msg: EventMsg::AnnounceAccepted } if s == serial => break Ok(()),
+                Event { serial: Some(s), msg: EventMsg::SessionToken { token } } if s == serial => {
+                    self.session_token = Some(token);
+                },
+                Event { serial: Some(s), msg: EventMsg::AnnounceRejected { reason } } if s == serial => break Err(
+                    std::io::Error::new(std::io::ErrorKind::PermissionDenied, AnnounceRejected { reason })
+                ),
+                event => unrelated.push(event),
+            }
+        };
+
+        for event in unrelated.into_iter().rev() {
+            self.pending_events.push_front(event);
+        }
+        result
+    }
+
+    /// Sends a request to the server. Returns the serial that replies to it will carry.
+    pub fn send(&mut self, msg: RequestMsg) -> Result<u32, std::io::Error> {
+        let serial = self.serials.next_serial();
+        self.channel.send_request(Request { serial, msg }, Vec::new())?;
+        Ok(serial)
+    }
+
+    /// Sends a request and blocks until the server replies to it, then returns the reply. Events that arrive in
+    /// the meantime, including replies to other requests, are kept for `recv_event`. If the server answers the
+    /// request with several events, only the first one is returned; the rest also go to `recv_event`.
+    pub fn send_and_wait(&mut self, msg: RequestMsg) -> Result<EventMsg, std::io::Error> {
+        let serial = self.send(msg)?;
+        let mut unrelated = Vec::new();
+        let reply = loop {
+            let event = self.recv_event()?;
+            match event {
+                Event { serial: Some(s), msg } if s == serial => break msg,
+                event => unrelated.push(event),
+            }
+        };
+
+        for event in unrelated.into_iter().rev() {
+            self.pending_events.push_front(event);
+        }
+        Ok(reply)
+    }
+
+    /// Blocks until the server sends an event. Pings get answered automatically and are not returned.
+    pub fn recv_event(&mut self) -> Result<Event, std::io::Error> {
+        loop {
+            let event = self.next_event()?;
+            match event.msg {
+                EventMsg::Ping { nonce } => { self.send(RequestMsg::Pong { nonce })?; },
+                _ => return Ok(event),
+            }
+        }
+    }
+
+    pub fn channel(&self) -> &StreamChannel {
+        &self.channel
+    }
+
+    fn next_event(&mut self) -> Result<Event, std::io::Error> {
+        loop {
+            if let Some(event) = self.pending_events.pop_front() {
+                return Ok(event);
+            }
+            self.wait_readable()?;
+            // The events in front of one that cannot be decoded still get returned, after the error.
+            for message in self.channel.recv_events()? {
+                self.pending_events.push_back(message?.msg);
+            }
+        }
+    }
+
+    /// Blocks until the channel can be read from, sending queued requests whenever possible in the meantime.
+    fn wait_readable(&mut self) -> Result<(), std::io::Error> {
+        loop {
+            let mut flags = PollFlags::IN;
+            if self.channel.has_pending_writes() {
+                flags |= PollFlags::OUT;
+            }
+            let mut to_poll = [PollFd::new(&self.channel, flags)];
+            retry_on_interrupt(|| rustix::event::poll(&mut to_poll, -1))?;
+            let revents = to_poll[0].revents();
+
+            if revents.contains(PollFlags::OUT) {
+                self.channel.flush()?;
+            }
+            // Errors and hangups are reported by the subsequent read.
+            if revents.intersects(PollFlags::IN | PollFlags::ERR | PollFlags::HUP) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The server refused to accept the client, either when it said hello or when it announced itself. The kind of
+/// the `std::io::Error` wrapping it tells why.
+#[derive(Debug)]
+pub struct AnnounceRejected {
+    pub reason: String,
+}
+
+impl std::fmt::Display for AnnounceRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The server refused the client: {}", self.reason)
+    }
+}
+
+impl std::error::Error for AnnounceRejected {}
+
+/// Why `EventLoop::run` returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Disconnect {
+    /// The callback asked the loop to stop. The connection is still open.
+    Stopped,
+    /// The server closed the channel.
+    ServerClosed,
+}
+
+/// Dispatches the events of a server to a callback until the callback or the server ends the conversation.
+///
+/// ```no_run
+/// use std::ops::ControlFlow;
+/// use libuio::client::{EventLoop, UioClient};
+/// use libuio::message::EventMsg;
+///
+/// let client = UioClient::connect(&libuio::socket::default_socket_path()).unwrap();
+/// let mut event_loop = EventLoop::new(client);
+/// event_loop.client().announce("Example").unwrap();
+/// event_loop.run(|_, event| match event.msg {
+///     EventMsg::Shutdown => ControlFlow::Break(()),
+///     _ => ControlFlow::Continue(()),
+/// }).unwrap();
+/// ```
+pub struct EventLoop {
+    client: UioClient,
+}
+
+impl EventLoop {
+    pub fn new(client: UioClient) -> Self {
+        EventLoop { client }
+    }
+
+    /// Sends a request to the server. Returns the serial that replies to it will carry.
+    pub fn send_request(&mut self, msg: RequestMsg) -> Result<u32, std::io::Error> {
+        self.client.send(msg)
+    }
+
+    pub fn client(&mut self) -> &mut UioClient {
+        &mut self.client
+    }
+
+    /// Calls `on_event` for every event the server sends, until it returns `Break` or the server closes the
+    /// channel. The callback gets the loop itself, so it can send requests in response. Pings are answered
+    /// automatically and never reach the callback.
+    pub fn run(
+        &mut self, mut on_event: impl FnMut(&mut EventLoop, Event) -> ControlFlow<()>,
+    ) -> Result<Disconnect, std::io::Error> {
+        loop {
+            let event = match self.client.recv_event() {
+                Ok(event) => event,
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => return Ok(Disconnect::ServerClosed),
+                Err(err) => return Err(err),
+            };
+            if on_event(self, event).is_break() {
+                return Ok(Disconnect::Stopped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{ClientInfo, PROTOCOL_VERSION};
+    use crate::socket::{Message, StreamSocket};
+    use crate::test_utils::unique_socket_path;
+
+    use super::*;
+
+    #[test]
+    fn reconnects_after_server_restart() {
+        let path = unique_socket_path();
+        let policy = RetryPolicy { max_attempts: Some(500), ..RetryPolicy::default() };
+
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let mut channel = connect_with_retry(&path, &policy).unwrap();
+        let (accepted, _) = socket.accept().unwrap();
+
+        // Kill the server.
+        drop(accepted);
+        drop(socket);
+        let Err(err) = channel.read_packets() else { panic!("The channel survived the server.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+        // Restart it a little later.
+        let server_path = path.clone();
+        let server = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            StreamSocket::open(server_path).unwrap()
+        });
+
+        connect_with_retry(&path, &policy).unwrap();
+        let socket = server.join().unwrap();
+        assert!(socket.accept().is_ok());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let policy = RetryPolicy { initial_delay: Duration::from_millis(1), max_attempts: Some(3), ..RetryPolicy::default() };
+        let err = connect_with_retry(&unique_socket_path(), &policy).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn busy_server_is_retried_with_backoff_until_the_attempts_run_out() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let policy = RetryPolicy { initial_delay: Duration::from_millis(20), max_attempts: Some(3), ..RetryPolicy::default() };
+            let start = std::time::Instant::now();
+            let err = UioClient::connect_with_retry(&path, &policy).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+            assert!(err.get_ref().unwrap().is::<AnnounceRejected>());
+            // Two delays between three attempts: 20ms and 40ms.
+            assert!(start.elapsed() >= Duration::from_millis(60));
+        });
+
+        // Play the part of a server that is at its client limit.
+        let mut turned_away = Vec::new();
+        while !client.is_finished() {
+            let mut to_poll = [PollFd::new(&socket, PollFlags::IN)];
+            rustix::event::poll(&mut to_poll, 10).unwrap();
+            if let Ok((mut server, _)) = socket.accept() {
+                server.send_event(Event { serial: None, msg: EventMsg::ServerBusy }, Vec::new()).unwrap();
+                turned_away.push(server);
+            }
+        }
+        client.join().unwrap();
+        assert_eq!(turned_away.len(), 3);
+    }
+
+    /// Blocks until the channel has received at least one request.
+    fn wait_for_requests(channel: &mut StreamChannel) -> Vec<Message<Request>> {
+        loop {
+            let mut to_poll = [PollFd::new(&*channel, PollFlags::IN)];
+            rustix::event::poll(&mut to_poll, -1).unwrap();
+            let requests: Vec<_> = channel.recv_requests().unwrap().into_iter().map(Result::unwrap).collect();
+            if !requests.is_empty() {
+                return requests;
+            }
+        }
+    }
+
+    #[test]
+    fn uio_client_handshake() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut client = UioClient::connect(&path).unwrap();
+            client.announce("Test").unwrap();
+        });
+
+        // Play the part of the server.
+        let mut to_poll = [PollFd::new(&socket, PollFlags::IN)];
+        rustix::event::poll(&mut to_poll, -1).unwrap();
+        let (mut server, _) = socket.accept().unwrap();
+
+        let hello = wait_for_requests(&mut server).remove(0).msg;
+        assert!(matches!(hello.msg, RequestMsg::Hello(_)));
+        server.send_event(Event { serial: Some(hello.serial), msg: EventMsg::Hello(HelloMsg::current()) }, Vec::new()).unwrap();
+
+        let announce = wait_for_requests(&mut server).remove(0).msg;
+        assert!(matches!(announce.msg, RequestMsg::Announce(_)));
+        server.send_event(Event { serial: None, msg: EventMsg::Ping { nonce: 7 } }, Vec::new()).unwrap();
+        server.send_event(Event { serial: Some(announce.serial), msg: EventMsg::AnnounceAccepted }, Vec::new()).unwrap();
+
+        let pong = wait_for_requests(&mut server).remove(0).msg;
+        assert!(matches!(pong.msg, RequestMsg::Pong { nonce: 7 }));
+        client.join().unwrap();
+    }
+
+    /// Accepts a client and answers its hello. Returns the server side of the channel.
+    fn accept_and_greet(socket: &StreamSocket) -> StreamChannel {
+        let mut to_poll = [PollFd::new(socket, PollFlags::IN)];
+        rustix::event::poll(&mut to_poll, -1).unwrap();
+        let (mut server, _) = socket.accept().unwrap();
+
+        let hello = wait_for_requests(&mut server).remove(0).msg;
+        server.send_event(Event { serial: Some(hello.serial), msg: EventMsg::Hello(HelloMsg::current()) }, Vec::new()).unwrap();
+        server
+    }
+
+    #[test]
+    fn version_mismatch_is_a_rejection() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let err = UioClient::connect(&path).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+            let rejected = err.get_ref().unwrap().downcast_ref::<AnnounceRejected>().unwrap();
+            assert!(rejected.reason.contains(&(PROTOCOL_VERSION + 1).to_string()));
+        });
+
+        let mut to_poll = [PollFd::new(&socket, PollFlags::IN)];
+        rustix::event::poll(&mut to_poll, -1).unwrap();
+        let (mut server, _) = socket.accept().unwrap();
+        let hello = wait_for_requests(&mut server).remove(0).msg;
+        let msg = EventMsg::VersionMismatch { server_version: PROTOCOL_VERSION + 1 };
+        server.send_event(Event { serial: Some(hello.serial), msg }, Vec::new()).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn rejected_announce_is_an_error() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut client = UioClient::connect(&path).unwrap();
+            let err = client.announce("Impostor").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+            let rejected = err.get_ref().unwrap().downcast_ref::<AnnounceRejected>().unwrap();
+            assert_eq!(rejected.reason, "Name taken");
+
+            // The event that arrived before the rejection has not been lost.
+            assert!(matches!(client.recv_event().unwrap().msg, EventMsg::Shutdown));
+        });
+
+        let mut server = accept_and_greet(&socket);
+        let announce = wait_for_requests(&mut server).remove(0).msg;
+        server.send_event(Event { serial: None, msg: EventMsg::Shutdown }, Vec::new()).unwrap();
+        let reason = "Name taken".to_owned();
+        server.send_event(Event { serial: Some(announce.serial), msg: EventMsg::AnnounceRejected { reason } }, Vec::new()).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn session_token_can_be_resumed_on_a_new_connection() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut client = UioClient::connect(&path).unwrap();
+            client.announce("Forgetful").unwrap();
+            let token = client.session_token().unwrap();
+            drop(client);
+
+            let mut client = UioClient::connect(&path).unwrap();
+            client.resume(token).unwrap();
+            assert_eq!(client.session_token(), Some(token));
+        });
+
+        let mut server = accept_and_greet(&socket);
+        let announce = wait_for_requests(&mut server).remove(0).msg;
+        server.send_event(Event { serial: Some(announce.serial), msg: EventMsg::SessionToken { token: 42 } }, Vec::new()).unwrap();
+        server.send_event(Event { serial: Some(announce.serial), msg: EventMsg::AnnounceAccepted }, Vec::new()).unwrap();
+
+        let mut server = accept_and_greet(&socket);
+        let resume = wait_for_requests(&mut server).remove(0).msg;
+        assert!(matches!(resume.msg, RequestMsg::Resume { token: 42 }));
+        server.send_event(Event { serial: Some(resume.serial), msg: EventMsg::AnnounceAccepted }, Vec::new()).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn send_and_wait_returns_the_reply_to_its_own_request() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let info = |id| ClientInfo { id, name: None, pid: 0, connected_for: Duration::ZERO };
+
+        let client = std::thread::spawn(move || {
+            let mut client = UioClient::connect(&path).unwrap();
+            client.send(RequestMsg::ListClients).unwrap();
+            let reply = client.send_and_wait(RequestMsg::ListClients).unwrap();
+            assert!(matches!(reply, EventMsg::ClientList { ref clients } if clients[..] == [info(2)]));
+
+            // The reply to the first request was not lost.
+            let event = client.recv_event().unwrap();
+            assert!(matches!(event.msg, EventMsg::ClientList { ref clients } if clients[..] == [info(1)]));
+        });
+
+        let mut server = accept_and_greet(&socket);
+        let mut requests = Vec::new();
+        while requests.len() < 2 {
+            requests.extend(wait_for_requests(&mut server).into_iter().map(|message| message.msg));
+        }
+        for (request, id) in requests.iter().zip([1, 2]) {
+            let event = Event { serial: Some(request.serial), msg: EventMsg::ClientList { clients: vec![info(id)] } };
+            server.send_event(event, Vec::new()).unwrap();
+        }
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn event_loop_runs_until_told_to_stop() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+
+        let client = std::thread::spawn(move || {
+            let mut event_loop = EventLoop::new(UioClient::connect(&path).unwrap());
+            event_loop.client().announce("Looping").unwrap();
+            let stopped = event_loop.run(|event_loop, event| match event.msg {
+                EventMsg::Shutdown => {
+                    event_loop.send_request(RequestMsg::Goodbye { reason: None }).unwrap();
+                    ControlFlow::Break(())
+                },
+                _ => ControlFlow::Continue(()),
+            });
+            assert_eq!(stopped.unwrap(), Disconnect::Stopped);
+
+            let closed = event_loop.run(|_, event| panic!("Unexpected event: {event:?}"));
+            assert_eq!(closed.unwrap(), Disconnect::ServerClosed);
+        });
+
+        let mut server = accept_and_greet(&socket);
+        let announce = wait_for_requests(&mut server).remove(0).msg;
+        server.send_event(Event { serial: Some(announce.serial), msg: EventMsg::AnnounceAccepted }, Vec::new()).unwrap();
+        server.send_event(Event { serial: None, msg: EventMsg::Shutdown }, Vec::new()).unwrap();
+
+        let goodbye = wait_for_requests(&mut server).remove(0).msg;
+        assert!(matches!(goodbye.msg, RequestMsg::Goodbye { .. }));
+        drop(server);
+        client.join().unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+//! Decides how messages get turned into the bytes of a packet and back.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::socket::PacketKind;
+
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The compact binary codec that is used on the wire by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(value).map_err(CodecError::Bincode)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::Bincode)
+    }
+}
+
+/// A human-readable codec, useful for debugging and for talking to programs that are not written in Rust.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(value).map_err(CodecError::Json)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::Json)
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Bincode(bincode::Error),
+    Json(serde_json::Error),
+    /// The packet carries more file descriptors than can be transmitted along with its data.
+    TooManyFds { count: usize, max: usize },
+    /// The encoded message is longer than a packet may be.
+    PayloadTooLarge { len: usize, max: usize },
+    /// The packet holds a different kind of message than the one it was decoded as.
+    UnexpectedKind { expected: PacketKind, found: PacketKind },
+    /// The header of a packet has a kind that does not exist.
+    UnknownKind { tag: u8 },
+    /// The message refers to an attached file descriptor that the packet does not carry.
+    FdIndexOutOfRange { index: u32, fd_count: usize },
+    /// The payload of a packet does not match the checksum in its header.
+    #[cfg(feature = "checksum")]
+    ChecksumMismatch { expected: u32, found: u32 },
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Bincode(err) => write!(f, "Failed to encode or decode bincode: {err}"),
+            CodecError::Json(err) => write!(f, "Failed to encode or decode JSON: {err}"),
+            CodecError::TooManyFds { count, max } => write!(f, "Cannot send {count} file descriptors in a packet that can carry at most {max}."),
+            CodecError::PayloadTooLarge { len, max } => write!(f, "Cannot send a packet of {len} bytes; packets may be at most {max} bytes long."),
+            CodecError::UnexpectedKind { expected, found } => write!(f, "Expected a packet of kind {expected:?}, but got one of kind {found:?}."),
+            CodecError::UnknownKind { tag } => write!(f, "Received a packet of the unknown kind {tag}."),
+            CodecError::FdIndexOutOfRange { index, fd_count } => write!(f, "The message refers to file descriptor {index}, but the packet only carries {fd_count}."),
+            #[cfg(feature = "checksum")]
+            CodecError::ChecksumMismatch { expected, found } => write!(f, "The packet's checksum is {found:#010x}, but its header says {expected:#010x}."),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Bincode(err) => Some(err),
+            CodecError::Json(err) => Some(err),
+            CodecError::TooManyFds { .. }
+            | CodecError::PayloadTooLarge { .. }
+            | CodecError::UnexpectedKind { .. }
+            | CodecError::UnknownKind { .. }
+            | CodecError::FdIndexOutOfRange { .. } => None,
+            #[cfg(feature = "checksum")]
+            CodecError::ChecksumMismatch { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{AnnounceMsg, Request, RequestMsg};
+
+    #[test]
+    fn json_round_trip() {
+        let request = Request { serial: 3, msg: RequestMsg::Announce(AnnounceMsg { name: "Json".to_owned() }) };
+        let bytes = JsonCodec.encode(&request).unwrap();
+        assert!(std::str::from_utf8(&bytes).unwrap().contains("\"Json\""));
+
+        let decoded: Request = JsonCodec.decode(&bytes).unwrap();
+        assert!(matches!(decoded, Request { serial: 3, msg: RequestMsg::Announce(AnnounceMsg { ref name }) } if name == "Json"));
+    }
+}
@@ -0,0 +1,49 @@
+//! The error type for operations that can fail either on the socket or while encoding or decoding a message.
+
+use crate::codec::CodecError;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Codec(CodecError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Codec(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => err.source(),
+            Error::Codec(err) => err.source(),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(err: CodecError) -> Self {
+        Error::Codec(err)
+    }
+}
+
+/// Messages that cannot be encoded or decoded count as invalid data.
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            Error::Codec(err) => std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Splits the byte stream of a channel into packets.
+//!
+//! This does not touch any file descriptor besides the ones it is handed, so it can be fed arbitrary input
+//! without a socket. `StreamChannel` feeds it whatever it reads from its socket.
+
+use std::os::fd::OwnedFd;
+
+use crate::codec::CodecError;
+use crate::socket::{Packet, PacketKind, MAX_FDS_PER_PACKET, MAX_FDS_PER_SYSCALL, MAX_PAYLOAD_LEN};
+
+/// The header is followed by a CRC32 of the data if the `checksum` feature is enabled.
+pub(crate) const PACKET_HEADER_LEN: usize = if cfg!(feature = "checksum") { 11 } else { 7 };
+/// The maximum amount of received file descriptors that may wait for the packet they belong to. Enough for an
+/// incomplete packet plus whatever arrives with the next syscall.
+pub(crate) const MAX_BUFFERED_FDS: usize = MAX_FDS_PER_PACKET + MAX_FDS_PER_SYSCALL;
+
+/// Holds the data read from a channel until it gets sorted into packets.
+#[derive(Default)]
+pub struct FrameReader {
+    /// Bytes read from this socket. Each packet has the following structure:
+    /// u32 (low endian) containing the length of the packet, excluding the header.
+    /// u16 (low endian) containing the amount of file descriptors sent with this packet
+    /// u8 containing the PacketKind
+    /// u32 (low endian) containing the CRC32 of the packet payload, only with the `checksum` feature
+    /// arbitrary bytes equal to the length of the packet payload
+    data: Vec<u8>,
+    /// File descriptors read from the socket that have not been associated with a complete packet yet.
+    fds: Vec<OwnedFd>,
+    /// How many bytes at the front of `data` belong to complete packets whose checksum has been verified.
+    #[cfg(feature = "checksum")]
+    verified_len: usize,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader::default()
+    }
+
+    /// Appends bytes and file descriptors that were received together. Fails if any of the buffered packets has
+    /// an invalid header, if a packet fails its checksum, or if more file descriptors are waiting than the
+    /// packets can carry. After an error, the stream cannot be trusted anymore and the reader should be discarded.
+    pub fn feed(&mut self, bytes: &[u8], fds: Vec<OwnedFd>) -> Result<(), std::io::Error> {
+        self.data.extend_from_slice(bytes);
+        self.check_headers()?;
+        #[cfg(feature = "checksum")]
+        self.verify_checksums()?;
+
+        self.fds.extend(fds);
+        if self.fds.len() > MAX_BUFFERED_FDS {
+            // Closes them, so a misbehaving peer cannot keep them pinned in our process.
+            self.fds.clear();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData, "Received more file descriptors than the packets claim to carry.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the packets that are complete, which removes each packet from the reader as it
+    /// is yielded.
+    pub fn drain(&mut self) -> Packets<'_> {
+        Packets { buffer: self }
+    }
+
+    /// How many bytes are waiting for the rest of their packet, or to be drained.
+    pub fn buffered_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// How many file descriptors are waiting for the rest of their packet, or to be drained.
+    pub fn buffered_fd_count(&self) -> usize {
+        self.fds.len()
+    }
+
+    /// Checks the header of every packet in the buffer, not just the one at the front: otherwise a bad packet
+    /// that arrived together with good ones would only be noticed once the peer sends something else.
+    fn check_headers(&self) -> Result<(), std::io::Error> {
+        let mut offset = 0;
+        while self.data.len() >= offset + PACKET_HEADER_LEN {
+            let packet_length = check_header(&self.data[offset ..])?;
+            offset += PACKET_HEADER_LEN + packet_length;
+        }
+        Ok(())
+    }
+
+    /// Verifies the checksums of the packets that have been completely received since the last call.
+    #[cfg(feature = "checksum")]
+    fn verify_checksums(&mut self) -> Result<(), std::io::Error> {
+        while self.data.len() >= self.verified_len + PACKET_HEADER_LEN {
+            let header = &self.data[self.verified_len ..];
+            let packet_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            if header.len() < PACKET_HEADER_LEN + packet_length {
+                break;
+            }
+            let expected = u32::from_le_bytes(header[7..11].try_into().unwrap());
+            let found = crc32(&header[PACKET_HEADER_LEN .. PACKET_HEADER_LEN + packet_length]);
+            if expected != found {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    CodecError::ChecksumMismatch { expected, found },
+                ));
+            }
+            self.verified_len += PACKET_HEADER_LEN + packet_length;
+        }
+        Ok(())
+    }
+
+    fn try_drain_packet(&mut self) -> Option<Packet> {
+        if self.data.len() < PACKET_HEADER_LEN {
+            return None;
+        }
+
+        let packet_length = u32::from_le_bytes(self.data[0..4].try_into().unwrap()) as usize;
+        if self.data.len() < PACKET_HEADER_LEN + packet_length {
+            return None;
+        }
+
+        let num_fds: usize = u16::from_le_bytes(self.data[4..6].try_into().unwrap()).into();
+        if self.fds.len() < num_fds {
+            return None;
+        }
+
+        // Packets of an unknown kind have been rejected by feed already.
+        let kind = PacketKind::try_from(self.data[6]).ok()?;
+
+        let packet_bytes = self.data[PACKET_HEADER_LEN .. PACKET_HEADER_LEN + packet_length].to_owned();
+        self.data.drain(.. PACKET_HEADER_LEN + packet_length);
+        #[cfg(feature = "checksum")]
+        {
+            self.verified_len -= PACKET_HEADER_LEN + packet_length;
+        }
+
+        let remaining_fds = self.fds.split_off(num_fds);
+        let packet_fds = std::mem::replace(&mut self.fds, remaining_fds);
+
+        Some(Packet {
+            data: packet_bytes, kind, fds: packet_fds
+        })
+    }
+}
+
+/// Iterates over the complete packets in a `FrameReader`. Each packet is removed from the reader at the moment
+/// it is yielded. Incomplete packets stay in the reader until more data has been fed.
+pub struct Packets<'a> {
+    buffer: &'a mut FrameReader,
+}
+
+impl Iterator for Packets<'_> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Packet> {
+        let packet = self.buffer.try_drain_packet();
+        #[cfg(feature = "wire-dump")]
+        if let Some(packet) = &packet {
+            log::debug!("{}", crate::socket::dump_packet("Received", packet));
+        }
+        packet
+    }
+}
+
+/// Fails if the header at the start of `header` claims that its packet is longer or carries more file
+/// descriptors than a packet may, or if it has an unknown kind. Returns the length of the packet's payload.
+fn check_header(header: &[u8]) -> Result<usize, std::io::Error> {
+    let packet_length = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    if packet_length > MAX_PAYLOAD_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CodecError::PayloadTooLarge { len: packet_length, max: MAX_PAYLOAD_LEN },
+        ));
+    }
+    let num_fds: usize = u16::from_le_bytes(header[4..6].try_into().unwrap()).into();
+    if num_fds > MAX_FDS_PER_PACKET {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            CodecError::TooManyFds { count: num_fds, max: MAX_FDS_PER_PACKET },
+        ));
+    }
+    PacketKind::try_from(header[6])
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    Ok(packet_length)
+}
+
+/// Adds the header to the packet for transmission and appends the result to `data`.
+pub(crate) fn append_framed(data: &mut Vec<u8>, packet: &Packet) {
+    data.reserve(packet.data.len() + PACKET_HEADER_LEN);
+    // MAX_PAYLOAD_LEN fits in an u32, and longer packets have been rejected by write_packets.
+    data.extend_from_slice(&u32::to_le_bytes(packet.data.len() as u32));
+    data.extend_from_slice(&u16::to_le_bytes(packet.fds.len().try_into().expect("Packet has too many file descriptors!")));
+    data.push(packet.kind as u8);
+    #[cfg(feature = "checksum")]
+    data.extend_from_slice(&u32::to_le_bytes(crc32(&packet.data)));
+    data.extend_from_slice(&packet.data);
+}
+
+/// The CRC32 (as used by zlib and Ethernet) of the bytes.
+#[cfg(feature = "checksum")]
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc = TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(packets: &[Packet]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for packet in packets {
+            append_framed(&mut data, packet);
+        }
+        data
+    }
+
+    #[test]
+    fn back_to_back_frames_are_split() {
+        let bytes = framed(&[Packet::new(vec![1, 2, 3], Vec::new()), Packet::new(Vec::new(), Vec::new())]);
+        let mut reader = FrameReader::new();
+        reader.feed(&bytes, Vec::new()).unwrap();
+
+        let packets: Vec<Packet> = reader.drain().collect();
+        assert_eq!(packets.iter().map(|packet| packet.data.clone()).collect::<Vec<_>>(), [vec![1, 2, 3], vec![]]);
+        assert_eq!(reader.buffered_len(), 0);
+    }
+
+    #[test]
+    fn frames_fed_byte_by_byte_are_reassembled() {
+        let bytes = framed(&[Packet::new(vec![7; 10], Vec::new())]);
+        let mut reader = FrameReader::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            assert!(reader.drain().next().is_none(), "Drained a packet after {i} bytes.");
+            reader.feed(&[*byte], Vec::new()).unwrap();
+        }
+        assert_eq!(reader.drain().next().unwrap().data, vec![7; 10]);
+    }
+
+    #[test]
+    fn huge_declared_length_is_rejected() {
+        let mut header = Vec::new();
+        header.extend_from_slice(&u32::to_le_bytes(MAX_PAYLOAD_LEN as u32 + 1));
+        header.resize(PACKET_HEADER_LEN, 0);
+
+        let Err(err) = FrameReader::new().feed(&header, Vec::new()) else { panic!("Accepted a huge packet.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_kind_behind_a_valid_frame_is_rejected() {
+        let mut bytes = framed(&[Packet::new(vec![1], Vec::new()), Packet::new(vec![2], Vec::new())]);
+        // The kind of the second packet.
+        bytes[PACKET_HEADER_LEN + 1 + 6] = 0xff;
+
+        let Err(err) = FrameReader::new().feed(&bytes, Vec::new()) else { panic!("Accepted an unknown kind.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn packet_waits_for_its_fds() {
+        let fd = || rustix::io::dup(std::io::stdin()).unwrap();
+        let bytes = framed(&[Packet::new(vec![1], vec![fd(), fd()])]);
+        let mut reader = FrameReader::new();
+        reader.feed(&bytes, vec![fd()]).unwrap();
+        assert!(reader.drain().next().is_none());
+
+        reader.feed(&[], vec![fd()]).unwrap();
+        assert_eq!(reader.drain().next().unwrap().fd_count(), 2);
+        assert_eq!(reader.buffered_fd_count(), 0);
+    }
+}
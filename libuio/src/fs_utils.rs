@@ -1,25 +1,99 @@
+use std::ffi::OsString;
+use std::os::fd::OwnedFd;
 use std::path::{Path, PathBuf};
 
+use rustix::fs::{AtFlags, Mode, OFlags};
+
 /// A to a file that will be deleted when this structure is dropped.
+///
+/// The directory containing the file is opened up front and the file gets unlinked relative to it, so that if
+/// some part of the path gets replaced in the meantime, we still delete exactly the file we created instead of
+/// whatever the path refers to by then. If the directory cannot be opened, the file gets removed by path.
 pub struct UnlinkOnDrop {
     path: PathBuf,
+    /// The directory containing the file and the name of the file within it.
+    parent: Option<(OwnedFd, OsString)>,
 }
 
 impl UnlinkOnDrop {
     pub fn new(path: PathBuf) -> Self {
-        Self { path }
+        let parent = Self::open_parent(&path);
+        Self { path, parent }
+    }
+
+    fn open_parent(path: &Path) -> Option<(OwnedFd, OsString)> {
+        let name = path.file_name()?.to_owned();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let flags = OFlags::PATH | OFlags::DIRECTORY | OFlags::CLOEXEC;
+        match rustix::fs::open(dir, flags, Mode::empty()) {
+            Ok(dir_fd) => Some((dir_fd, name)),
+            Err(err) => {
+                log::warn!("Failed to open the directory {}, will unlink by path: {err}", dir.display());
+                None
+            },
+        }
     }
-    
+
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
+
+    /// Removes the file. A file that has already been removed by someone else is not an error.
+    fn unlink(&self) -> Result<(), std::io::Error> {
+        let result = match &self.parent {
+            Some((dir_fd, name)) => rustix::fs::unlinkat(dir_fd, name.as_os_str(), AtFlags::empty())
+                .map_err(std::io::Error::from),
+            None => std::fs::remove_file(&self.path),
+        };
+        match result {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
 }
 
 impl Drop for UnlinkOnDrop {
     fn drop(&mut self) {
-        if let Err(err) = std::fs::remove_file(&self.path) {
-            eprintln!("Warning: failed to unlink the file {}: {err}", self.path.display());
+        if let Err(err) = self.unlink() {
+            log::warn!("Failed to unlink the file {}: {err}", self.path.display());
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::unique_socket_path;
+
+    #[test]
+    fn unlinks_the_original_file_after_the_directory_moved() {
+        let original_dir = unique_socket_path();
+        let moved_dir = unique_socket_path();
+        std::fs::create_dir(&original_dir).unwrap();
+        std::fs::write(original_dir.join("socket"), b"").unwrap();
+
+        let guard = UnlinkOnDrop::new(original_dir.join("socket"));
+        std::fs::rename(&original_dir, &moved_dir).unwrap();
+        // Someone else puts a file at the path we were given.
+        std::fs::create_dir(&original_dir).unwrap();
+        std::fs::write(original_dir.join("socket"), b"").unwrap();
+        drop(guard);
+
+        assert!(!moved_dir.join("socket").exists());
+        assert!(original_dir.join("socket").exists());
+
+        std::fs::remove_dir_all(&original_dir).unwrap();
+        std::fs::remove_dir_all(&moved_dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_is_not_an_error() {
+        let guard = UnlinkOnDrop::new(unique_socket_path());
+        // Drop only warns if this fails.
+        assert!(guard.unlink().is_ok());
+        drop(guard);
+    }
+}
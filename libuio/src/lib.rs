@@ -1,10 +1,21 @@
 #![allow(dead_code)]
 
+pub mod channel;
+pub mod client;
+pub mod codec;
+pub mod error;
+pub mod framing;
+pub mod shm;
 pub mod socket;
 pub mod message;
 
 mod fs_utils;
 
+pub use error::Error;
+
+#[cfg(test)]
+mod test_utils;
+
 #[macro_use]
 extern crate serde;
 
@@ -1,7 +1,87 @@
+use crate::codec::CodecError;
+
+/// The version of the wire format. Must be bumped whenever the packet framing or the layout of the messages
+/// changes in a way that older peers cannot understand.
+pub const PROTOCOL_VERSION: u32 = 17;
+
+/// A request together with the serial that the client assigned to it.
+///
+/// Similar to Wayland serials, the server echoes this serial in every event it sends as a direct reply
+/// to this request, so the client can figure out which reply belongs to which request.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub serial: u32,
+    pub msg: RequestMsg,
+}
+
+/// An event together with the serial of the request that caused it, if any.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Event {
+    /// None if the server sent this event on its own initiative rather than in reply to a request.
+    pub serial: Option<u32>,
+    pub msg: EventMsg,
+}
+
 /// Reusing Wayland terminology, requests are messages from the client to the server.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RequestMsg {
+    /// Must be the first request sent over a new channel.
+    Hello(HelloMsg),
     Announce(AnnounceMsg),
+    /// Tells the server that the client is about to close the channel.
+    Goodbye { reason: Option<String> },
+    /// The answer to a ping from the server, carrying the same nonce.
+    Pong { nonce: u64 },
+    /// Hands the server a read-only buffer of `len` bytes in shared memory. The buffer's file descriptor is
+    /// attached to the packet; see `libuio::shm::SharedBuffer`.
+    ShareBuffer { len: u64 },
+    /// Asks the server which clients are connected. The server answers with a client list, or with an error if
+    /// the client does not run as the same user as the server.
+    ListClients,
+    /// Sent instead of an announcement after reconnecting, to take over the name of the client that received
+    /// the session token. Answered like an announcement.
+    Resume { token: u64 },
+    /// Asks the server how much traffic it has exchanged with this client. Answered with stats.
+    GetStats,
+    /// Asks the server to deliver the events of the given topics from now on, in addition to the topics the
+    /// client has subscribed to before. See `EventMsg::topic`.
+    Subscribe { topics: Vec<String> },
+    /// Hands the server an image of `height` rows of `stride` bytes each in shared memory. The buffer's sealed
+    /// memfd is attached to the packet; see `libuio::shm::PixelBuffer`.
+    AttachBuffer { width: u32, height: u32, stride: u32, format: PixelFormat },
+}
+
+/// How the pixels of an attached buffer are laid out in memory.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 32 bits per pixel: blue, green, red and alpha, in that order in memory.
+    Argb8888,
+    /// Like `Argb8888`, except that the alpha byte is ignored.
+    Xrgb8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Argb8888 | PixelFormat::Xrgb8888 => 4,
+        }
+    }
+}
+
+/// The first message that both client and server send over a new channel, to make sure they speak the same
+/// version of the protocol.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HelloMsg {
+    pub version: u32,
+    /// A bitmask of optional protocol features. No optional features have been defined yet.
+    pub features: u32,
+}
+
+impl HelloMsg {
+    /// The hello message for the protocol version that this library implements.
+    pub fn current() -> Self {
+        HelloMsg { version: PROTOCOL_VERSION, features: 0 }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -12,5 +92,139 @@ pub struct AnnounceMsg {
 /// Events are messages from the server to the client.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum EventMsg {
+    /// Sent in reply to the client's hello if the server can speak the client's version.
+    Hello(HelloMsg),
+    /// Sent in reply to the client's hello if the server cannot speak the client's version. The server
+    /// closes the channel after sending this. Unlike `AnnounceRejected`, it tells the client which version to
+    /// speak instead.
+    VersionMismatch { server_version: u32 },
     AnnounceAccepted,
+    /// Sent right before `AnnounceAccepted`. If the channel breaks, the client can reconnect and resume its
+    /// session with this token, as long as it does so within the server's grace period.
+    SessionToken { token: u64 },
+    /// The server refused the client's announcement. It closes the channel after sending this.
+    AnnounceRejected { reason: String },
+    /// Sent periodically to check whether the client is still responsive. The client must answer with a pong
+    /// carrying the same nonce, or it will eventually be disconnected.
+    Ping { nonce: u64 },
+    /// The server is shutting down and will close the channel shortly.
+    Shutdown,
+    /// The server has too many clients already. It closes the channel right after sending this. Unlike
+    /// `AnnounceRejected`, it tells the client that trying again later may work.
+    ServerBusy,
+    /// The client did something wrong. The code is one of the `ERROR_*` constants; the message is meant for
+    /// humans. The server closes the channel after sending this.
+    Error { code: u32, message: String },
+    /// The clients that were connected when the server handled a `ListClients` request, including the client
+    /// that asked.
+    ClientList { clients: Vec<ClientInfo> },
+    /// A surface has new contents. Both fields are indices into the file descriptors attached to the packet:
+    /// the buffer holding the contents, and a fence that signals when the buffer is ready to be read.
+    SurfaceUpdate { buffer_index: u32, fence_index: u32 },
+    /// The traffic that the server had exchanged with the client when it handled a `GetStats` request. The
+    /// reply itself is not counted yet.
+    Stats { stats: ClientStats },
+    /// The pointer moved to the given position, in surface-local coordinates.
+    PointerMotion { x: f64, y: f64 },
+    /// A pointer button was pressed (`state` is true) or released.
+    PointerButton { button: u32, state: bool },
+    /// A key was pressed (`state` is true) or released.
+    Key { keycode: u32, state: bool },
 }
+
+/// The topic of client list updates.
+pub const TOPIC_CLIENTS: &str = "clients";
+/// The topic of surface updates.
+pub const TOPIC_SURFACES: &str = "surfaces";
+
+impl EventMsg {
+    /// The topic that a client must subscribe to in order to receive this event when the server sends it to
+    /// all clients. Events without a topic reach every client, because they concern the channel itself.
+    pub fn topic(&self) -> Option<&'static str> {
+        match self {
+            EventMsg::ClientList { .. } => Some(TOPIC_CLIENTS),
+            EventMsg::SurfaceUpdate { .. } => Some(TOPIC_SURFACES),
+            _ => None,
+        }
+    }
+
+    /// Whether a newer event of the same kind makes this one obsolete, so a server may drop this one if the
+    /// client has not received it yet by the time the newer one is sent.
+    pub fn is_coalescable(&self) -> bool {
+        matches!(self, EventMsg::ClientList { .. } | EventMsg::PointerMotion { .. })
+    }
+
+    /// Whether this event reports input from the user.
+    pub fn is_input(&self) -> bool {
+        matches!(self, EventMsg::PointerMotion { .. } | EventMsg::PointerButton { .. } | EventMsg::Key { .. })
+    }
+
+    /// The indices into the attached file descriptors that this event refers to.
+    pub fn fd_indices(&self) -> Vec<u32> {
+        match self {
+            EventMsg::SurfaceUpdate { buffer_index, fence_index } => vec![*buffer_index, *fence_index],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Fails if the event refers to file descriptors beyond the `fd_count` ones attached to its packet.
+    pub fn check_fd_indices(&self, fd_count: usize) -> Result<(), CodecError> {
+        match self.fd_indices().into_iter().find(|&index| index as usize >= fd_count) {
+            Some(index) => Err(CodecError::FdIndexOutOfRange { index, fd_count }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A snapshot of what the server knows about one of its clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Identifies the client for as long as it stays connected. Ids of disconnected clients may be reused.
+    pub id: u64,
+    /// The name the client announced itself with, if it has done so.
+    pub name: Option<String>,
+    /// The process id of the client at the time it connected.
+    pub pid: i32,
+    /// How long the client had been connected when the list was made.
+    pub connected_for: std::time::Duration,
+}
+
+/// How much the server has read from and written to a client's channel. Bytes count the packet payloads,
+/// not the headers that get added when framing them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ClientStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub packets_read: u64,
+    pub packets_written: u64,
+}
+
+/// The request could not be deserialized.
+pub const ERROR_UNPARSEABLE: u32 = 1;
+/// The request was understood, but makes no sense in the current state of the channel.
+pub const ERROR_UNKNOWN_REQUEST: u32 = 2;
+/// The client sent a request before completing the hello handshake.
+pub const ERROR_UNAUTHENTICATED: u32 = 3;
+/// The client attached a buffer that does not match its description, or whose contents could still change.
+pub const ERROR_INVALID_BUFFER: u32 = 4;
+/// The client asked for something that only clients running as the same user as the server may ask for.
+pub const ERROR_PERMISSION_DENIED: u32 = 5;
+
+/// Hands out serials for outgoing requests. Serials increase monotonically and wrap around on overflow.
+#[derive(Default)]
+pub struct SerialCounter {
+    next: u32,
+}
+
+impl SerialCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn next_serial(&mut self) -> u32 {
+        let serial = self.next;
+        self.next = self.next.wrapping_add(1);
+        serial
+    }
+}
+
@@ -0,0 +1,300 @@
+//! Buffers in shared memory that can be passed to the other side of a channel without copying their contents.
+
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+
+use rustix::fs::{MemfdFlags, SealFlags};
+use rustix::mm::{MapFlags, ProtFlags};
+
+use crate::message::{PixelFormat, RequestMsg};
+
+/// A memfd whose contents can no longer change. The sender fills it and seals it before sharing it, so the
+/// receiver can read it without worrying that it changes underneath.
+pub struct SharedBuffer {
+    fd: OwnedFd,
+    len: usize,
+}
+
+impl SharedBuffer {
+    /// Creates a sealed memfd holding a copy of `contents`. The name only shows up in debugging tools.
+    pub fn new(name: &str, contents: &[u8]) -> Result<SharedBuffer, std::io::Error> {
+        let fd = rustix::fs::memfd_create(name, MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING)?;
+        rustix::fs::ftruncate(&fd, contents.len() as u64)?;
+        let mut written = 0;
+        while written < contents.len() {
+            written += rustix::io::pwrite(&fd, &contents[written ..], written as u64)?;
+        }
+        rustix::fs::fcntl_add_seals(&fd, SealFlags::SHRINK | SealFlags::GROW | SealFlags::WRITE | SealFlags::SEAL)?;
+        Ok(SharedBuffer { fd, len: contents.len() })
+    }
+
+    /// Turns the file descriptor that came with a `ShareBuffer` request back into a buffer. Fails if there is
+    /// not exactly one file descriptor, if the file is shorter than the request claims, or if the sender could
+    /// still change the contents, see `check_seals`.
+    pub fn receive(len: u64, fds: Vec<OwnedFd>) -> Result<SharedBuffer, std::io::Error> {
+        let Ok([fd]) = <[OwnedFd; 1]>::try_from(fds) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData, "A shared buffer must come with exactly one file descriptor.",
+            ));
+        };
+        check_seals(&fd)?;
+        let size = rustix::fs::fstat(&fd)?.st_size as u64;
+        let len = usize::try_from(len).ok().filter(|_| len <= size).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidData, format!("A shared buffer of {size} bytes cannot hold {len} bytes."),
+        ))?;
+        Ok(SharedBuffer { fd, len })
+    }
+
+    /// The request that shares this buffer, together with the file descriptor that must be sent with it.
+    pub fn into_request(self) -> (RequestMsg, Vec<OwnedFd>) {
+        (RequestMsg::ShareBuffer { len: self.len as u64 }, vec![self.fd])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maps the buffer into memory, read-only.
+    pub fn map(&self) -> Result<Mapping, std::io::Error> {
+        if self.len == 0 {
+            return Ok(Mapping { ptr: std::ptr::null_mut(), len: 0 });
+        }
+        // SAFETY: we let the kernel pick the address, so no existing memory gets replaced.
+        let ptr = unsafe {
+            rustix::mm::mmap(std::ptr::null_mut(), self.len, ProtFlags::READ, MapFlags::SHARED, &self.fd, 0)?
+        };
+        Ok(Mapping { ptr, len: self.len })
+    }
+}
+
+/// An image in a SharedBuffer, as attached with an `AttachBuffer` request. The buffer holds `height` rows of
+/// `stride` bytes each, and nothing more.
+pub struct PixelBuffer {
+    buffer: SharedBuffer,
+    width: u32,
+    height: u32,
+    stride: u32,
+    format: PixelFormat,
+}
+
+impl PixelBuffer {
+    /// Creates a sealed memfd holding a copy of `contents`, which must be exactly `stride * height` bytes.
+    pub fn new(
+        name: &str, width: u32, height: u32, stride: u32, format: PixelFormat, contents: &[u8],
+    ) -> Result<PixelBuffer, std::io::Error> {
+        let len = image_len(width, height, stride, format)?;
+        if contents.len() != len {
+            let message = format!("An image of {len} bytes cannot hold {} bytes.", contents.len());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+        }
+        let buffer = SharedBuffer::new(name, contents)?;
+        Ok(PixelBuffer { buffer, width, height, stride, format })
+    }
+
+    /// Turns the file descriptor that came with an `AttachBuffer` request back into a buffer. Fails if there is
+    /// not exactly one file descriptor, if the rows are too short for their pixels, if the file is not exactly
+    /// `stride * height` bytes, or if the sender could still change the contents, see `check_seals`.
+    pub fn receive(
+        width: u32, height: u32, stride: u32, format: PixelFormat, fds: Vec<OwnedFd>,
+    ) -> Result<PixelBuffer, std::io::Error> {
+        let len = image_len(width, height, stride, format)?;
+        let buffer = SharedBuffer::receive(len as u64, fds)?;
+        let size = rustix::fs::fstat(&buffer)?.st_size as u64;
+        if size != len as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData, format!("An image of {len} bytes cannot be a file of {size} bytes."),
+            ));
+        }
+        Ok(PixelBuffer { buffer, width, height, stride, format })
+    }
+
+    /// The request that attaches this buffer, together with the file descriptor that must be sent with it.
+    pub fn into_request(self) -> (RequestMsg, Vec<OwnedFd>) {
+        let PixelBuffer { buffer, width, height, stride, format } = self;
+        (RequestMsg::AttachBuffer { width, height, stride, format }, vec![buffer.fd])
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.stride
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// Maps the image into memory, read-only.
+    pub fn map(&self) -> Result<Mapping, std::io::Error> {
+        self.buffer.map()
+    }
+}
+
+impl AsFd for PixelBuffer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.buffer.as_fd()
+    }
+}
+
+/// How many bytes an image takes. Fails if its rows are too short to hold `width` pixels.
+fn image_len(width: u32, height: u32, stride: u32, format: PixelFormat) -> Result<usize, std::io::Error> {
+    let min_stride = u64::from(width) * u64::from(format.bytes_per_pixel());
+    if u64::from(stride) < min_stride {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData, format!("Rows of {stride} bytes cannot hold {width} pixels."),
+        ));
+    }
+    usize::try_from(u64::from(stride) * u64::from(height)).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidData, "The image does not fit in memory.",
+    ))
+}
+
+/// Makes sure that the file can neither shrink nor be written to anymore. Otherwise the sender could change the
+/// buffer while we read it, or make us crash with SIGBUS by truncating it while it is mapped.
+///
+/// F_SEAL_FUTURE_WRITE is not enough: it only prevents new writable mappings, so a mapping that the sender made
+/// before sealing could still change the contents.
+pub fn check_seals(fd: impl AsFd) -> Result<(), std::io::Error> {
+    let not_sealed = || std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied, "The shared buffer has not been sealed against writes and shrinking.",
+    );
+    // Files that do not support sealing at all cannot be trusted either.
+    let seals = rustix::fs::fcntl_get_seals(fd).map_err(|_| not_sealed())?;
+    if !seals.contains(SealFlags::SHRINK | SealFlags::WRITE) {
+        return Err(not_sealed());
+    }
+    Ok(())
+}
+
+impl AsFd for SharedBuffer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// The contents of a SharedBuffer, mapped into memory. Unmapped when dropped.
+pub struct Mapping {
+    ptr: *mut std::ffi::c_void,
+    len: usize,
+}
+
+impl std::ops::Deref for Mapping {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        // SAFETY: the mapping is valid for as long as self lives. The contents cannot change because
+        // `check_seals` or `SharedBuffer::new` made sure that F_SEAL_WRITE is set, which the kernel only allows
+        // once no writable mappings of the file exist anymore.
+        unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        // SAFETY: the pointer and length came from mmap, and no references to the contents outlive self.
+        if let Err(err) = unsafe { rustix::mm::munmap(self.ptr, self.len) } {
+            log::warn!("Failed to unmap a shared buffer: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::channel::Channel;
+    use crate::message::Request;
+    use crate::socket::{StreamChannel, StreamSocket};
+    use crate::test_utils::unique_socket_path;
+
+    use super::*;
+
+    #[test]
+    fn shared_buffer_round_trip() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let mut client = StreamChannel::open(&path).unwrap();
+        let (mut server, _) = socket.accept().unwrap();
+
+        let buffer = SharedBuffer::new("test", b"Shared contents").unwrap();
+        let (msg, fds) = buffer.into_request();
+        client.send_request(Request { serial: 0, msg }, fds).unwrap();
+
+        let message = loop {
+            if let Some(message) = server.recv_requests().unwrap().pop() {
+                break message.unwrap();
+            }
+        };
+        let RequestMsg::ShareBuffer { len } = message.msg.msg else { panic!("Received the wrong request.") };
+        let received = SharedBuffer::receive(len, message.fds).unwrap();
+        assert_eq!(&*received.map().unwrap(), b"Shared contents");
+    }
+
+    #[test]
+    fn unsealed_buffer_is_rejected() {
+        let fd = rustix::fs::memfd_create("unsealed", MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING).unwrap();
+        rustix::fs::ftruncate(&fd, 4).unwrap();
+        assert!(check_seals(&fd).is_err());
+
+        // Sealing against shrinking alone is not enough.
+        rustix::fs::fcntl_add_seals(&fd, SealFlags::SHRINK).unwrap();
+        let err = SharedBuffer::receive(4, vec![fd]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn buffer_sealed_only_against_future_writes_is_rejected() {
+        let fd = rustix::fs::memfd_create("future", MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING).unwrap();
+        rustix::fs::ftruncate(&fd, 4).unwrap();
+        rustix::fs::fcntl_add_seals(&fd, SealFlags::SHRINK | SealFlags::GROW | SealFlags::FUTURE_WRITE).unwrap();
+        let err = SharedBuffer::receive(4, vec![fd]).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn sealed_buffer_is_accepted() {
+        let buffer = SharedBuffer::new("sealed", b"sealed").unwrap();
+        assert!(check_seals(&buffer).is_ok());
+
+        let (_, fds) = buffer.into_request();
+        assert_eq!(SharedBuffer::receive(6, fds).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn pixel_buffer_must_match_its_file_exactly() {
+        let buffer = SharedBuffer::new("pixels", &[0; 32]).unwrap();
+        let (_, fds) = buffer.into_request();
+        let err = PixelBuffer::receive(2, 3, 8, PixelFormat::Argb8888, fds).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        let buffer = PixelBuffer::new("pixels", 2, 4, 8, PixelFormat::Argb8888, &[0; 32]).unwrap();
+        let (RequestMsg::AttachBuffer { width, height, stride, format }, fds) = buffer.into_request() else {
+            panic!("Created the wrong request.")
+        };
+        assert_eq!(PixelBuffer::receive(width, height, stride, format, fds).unwrap().height(), 4);
+
+        // Rows of 7 bytes cannot hold two pixels of 4 bytes.
+        assert!(PixelBuffer::new("pixels", 2, 4, 7, PixelFormat::Xrgb8888, &[0; 28]).is_err());
+    }
+
+    #[test]
+    fn buffer_longer_than_its_file_is_rejected() {
+        let buffer = SharedBuffer::new("short", b"short").unwrap();
+        let (_, fds) = buffer.into_request();
+        assert!(SharedBuffer::receive(6, fds).is_err());
+        assert!(SharedBuffer::receive(5, Vec::new()).is_err());
+    }
+}
@@ -1,102 +1,345 @@
-/// TODO: Obviously, this socket needs to go elsewhere.
+/// The socket path that gets used if neither `UIO_SOCKET` nor `XDG_RUNTIME_DIR` are set.
 pub const DEFAULT_UIO_SOCKET_PATH: &str = "/tmp/uio/socket";
 
-use std::io::IoSlice;
-use std::os::fd::{OwnedFd, AsFd, BorrowedFd};
+/// Returns the path at which the server should listen and to which clients should connect: the `UIO_SOCKET`
+/// environment variable if set, otherwise `$XDG_RUNTIME_DIR/uio/socket`, otherwise `DEFAULT_UIO_SOCKET_PATH`.
+pub fn default_socket_path() -> PathBuf {
+    resolve_socket_path(std::env::var_os("UIO_SOCKET"), std::env::var_os("XDG_RUNTIME_DIR"))
+}
+
+fn resolve_socket_path(uio_socket: Option<OsString>, xdg_runtime_dir: Option<OsString>) -> PathBuf {
+    // Empty variables are treated as unset, like most programs do for XDG_RUNTIME_DIR.
+    let uio_socket = uio_socket.filter(|path| !path.is_empty());
+    let xdg_runtime_dir = xdg_runtime_dir.filter(|path| !path.is_empty());
+
+    match (uio_socket, xdg_runtime_dir) {
+        (Some(path), _) => PathBuf::from(path),
+        (None, Some(dir)) => PathBuf::from(dir).join("uio").join("socket"),
+        (None, None) => PathBuf::from(DEFAULT_UIO_SOCKET_PATH),
+    }
+}
+
+/// Where a socket can be found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// A socket file in the filesystem.
+    Path(PathBuf),
+    /// A name in Linux's abstract socket namespace. Such sockets have no file, so they need no cleanup and
+    /// cannot be left behind by a server that crashed.
+    Abstract(Vec<u8>),
+}
+
+impl From<PathBuf> for Address {
+    fn from(path: PathBuf) -> Self {
+        Address::Path(path)
+    }
+}
+
+impl From<&PathBuf> for Address {
+    fn from(path: &PathBuf) -> Self {
+        Address::Path(path.clone())
+    }
+}
+
+impl From<&Path> for Address {
+    fn from(path: &Path) -> Self {
+        Address::Path(path.to_owned())
+    }
+}
+
+impl Address {
+    fn to_socket_addr(&self) -> Result<rustix::net::SocketAddrUnix, std::io::Error> {
+        let socket_addr = match self {
+            Address::Path(path) => rustix::net::SocketAddrUnix::new(path)?,
+            Address::Abstract(name) => rustix::net::SocketAddrUnix::new_abstract_name(name)?,
+        };
+        Ok(socket_addr)
+    }
+
+    /// Returns None for unnamed sockets, such as the client end of a connection that was never bound.
+    fn from_socket_addr(socket_addr: Option<rustix::net::SocketAddrAny>) -> Option<Address> {
+        let Some(rustix::net::SocketAddrAny::Unix(socket_addr)) = socket_addr else { return None };
+        if let Some(path) = socket_addr.path() {
+            return Some(Address::Path(OsStr::from_bytes(path.to_bytes()).into()));
+        }
+        // The kernel reports unnamed sockets with an empty name, which is not a valid abstract name either.
+        socket_addr.abstract_name()
+            .filter(|name| !name.is_empty())
+            .map(|name| Address::Abstract(name.to_owned()))
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Path(path) => write!(f, "{}", path.display()),
+            Address::Abstract(name) => write!(f, "@{}", String::from_utf8_lossy(name)),
+        }
+    }
+}
+
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::fd::{OwnedFd, AsFd, BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use rustix::fd::AsRawFd;
-use rustix::fs::OFlags;
+use std::time::{Duration, Instant};
+use rustix::fs::{Mode, OFlags};
+use rustix::event::{PollFd, PollFlags};
 use rustix::io::FdFlags;
-use rustix::net::{RecvAncillaryBuffer, RecvAncillaryMessage, SendAncillaryBuffer, SendAncillaryMessage, SendFlags};
+use rustix::net::{RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer, SendAncillaryMessage, SendFlags};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
+use crate::channel::Channel;
+use crate::codec::{BincodeCodec, Codec, CodecError};
 use crate::fs_utils::UnlinkOnDrop;
-use crate::message::{EventMsg, RequestMsg};
+use crate::framing::{append_framed, FrameReader, PACKET_HEADER_LEN};
+use crate::message::{Event, Request};
+
+pub use crate::framing::Packets;
 
 /// A message that can be send through a StreamChannel. It is a vector of bytes that optionally contains
 /// space for file descriptors.
+///
+/// The fields are public, but new code should prefer the accessors and `into_parts`.
 pub struct Packet {
     /// The bytes without header that this packet contains.
     pub data: Vec<u8>,
+    /// What kind of message the data holds. Sent along in the header.
+    pub kind: PacketKind,
     pub fds: Vec<OwnedFd>,
 }
 
-/// Holds the data read from a channel until it gets sorted into packets.
-struct PartialPacket {
-    /// Bytes read from this socket. Each packet has the following structure:
-    /// u16 (low endian) containing the length of the packet, excluding the header.
-    /// u16 (low endian) containing the amount of file descriptors sent with this packet
-    /// arbitrary bytes equal to the length of the packet payload
-    data: Vec<u8>,
-    /// File descriptors read from the socket that have not been associated with a complete packet yet.
-    fds: Vec<OwnedFd>,
+/// What kind of message a packet carries. It is part of the header, so that a packet of one kind cannot be
+/// mistaken for a message of another kind that happens to decode without errors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PacketKind {
+    /// Bytes that were not encoded from a message. Cannot be decoded into requests or events.
+    #[default]
+    Raw = 0,
+    Request = 1,
+    Event = 2,
+    /// Reserved for messages about the channel itself.
+    Control = 3,
 }
 
-const PACKET_HEADER_LEN: usize = 4;
+impl TryFrom<u8> for PacketKind {
+    type Error = CodecError;
 
-impl PartialPacket {
-    fn try_drain_packet(&mut self) -> Option<Packet> {
-        if self.data.len() < PACKET_HEADER_LEN {
-            return None;
+    fn try_from(tag: u8) -> Result<PacketKind, CodecError> {
+        match tag {
+            0 => Ok(PacketKind::Raw),
+            1 => Ok(PacketKind::Request),
+            2 => Ok(PacketKind::Event),
+            3 => Ok(PacketKind::Control),
+            _ => Err(CodecError::UnknownKind { tag }),
         }
+    }
+}
 
-        let packet_length: usize = u16::from_le_bytes(self.data[0..2].try_into().unwrap()).into();
-        if self.data.len() < packet_length {
-            return None;
-        }
+impl std::fmt::Debug for Packet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The contents are left out on purpose: they can be big and are meaningless without decoding them.
+        // Only the `wire-dump` feature, which is meant for looking at them, shows them.
+        let mut debug = f.debug_struct("Packet");
+        debug
+            .field("kind", &self.kind)
+            .field("len", &self.len())
+            .field("fd_count", &self.fd_count());
+        #[cfg(feature = "wire-dump")]
+        debug.field("data", &self.data);
+        debug.finish()
+    }
+}
 
-        let num_fds: usize = u16::from_le_bytes(self.data[2..4].try_into().unwrap()).into();
-        if self.fds.len() < num_fds {
-            return None;
-        }
+/// The first file descriptor that systemd passes to socket-activated services.
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// The maximum length of the data of a single packet. Peers that announce longer packets get disconnected,
+/// so they cannot make us buffer an arbitrary amount of data.
+pub const MAX_PAYLOAD_LEN: usize = 16 * 1024 * 1024;
 
-        let packet_bytes = self.data[PACKET_HEADER_LEN .. PACKET_HEADER_LEN + packet_length].to_owned();
-        let remaining_bytes = self.data[PACKET_HEADER_LEN + packet_length ..].to_owned();
-        self.data = remaining_bytes;
+/// The maximum amount of file descriptors that get sent along with a single syscall.
+pub(crate) const MAX_FDS_PER_SYSCALL: usize = 32;
+/// The maximum amount of file descriptors that a single packet may carry. Packets with more file descriptors
+/// than fit in a single syscall get split over several ones, but peers must not be able to make us hold on to
+/// an arbitrary amount of file descriptors while waiting for the rest of a packet.
+pub const MAX_FDS_PER_PACKET: usize = 256;
+/// Packets get combined into a single syscall as long as their combined size stays below this limit.
+const MAX_BYTES_PER_SYSCALL: usize = 64 * 1024;
+/// How long dropping a channel waits for queued packets to be sent.
+const DROP_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// The maximum amount of bytes that get read with a single syscall.
+const RECEIVE_BUFFER_SIZE: usize = 16 * 1024;
+/// Enough space for the file descriptors and credentials that can arrive with a single syscall.
+const CONTROL_BUFFER_SIZE: usize = rustix::cmsg_space!(ScmRights(MAX_FDS_PER_SYSCALL), ScmCredentials(1));
 
-        let remaining_fds = self.fds.split_off(num_fds);
-        let packet_fds = std::mem::replace(&mut self.fds, remaining_fds);
+/// Describes a packet in full for the `wire-dump` feature.
+#[cfg(feature = "wire-dump")]
+pub(crate) fn dump_packet(direction: &str, packet: &Packet) -> String {
+    use std::fmt::Write;
 
-        Some(Packet {
-            data: packet_bytes, fds: packet_fds
-        })
+    let mut hex = String::with_capacity(packet.data.len() * 3);
+    for byte in &packet.data {
+        let _ = write!(hex, " {byte:02x}");
     }
+    format!("{direction} packet of {} bytes with {} fds:{hex}", packet.len(), packet.fd_count())
+}
 
-    /// Returns all complete packets stored in this buffer. Can return zero, one, or multiple packets.
-    fn drain_packets(&mut self) -> Vec<Packet> {
-        let mut result = Vec::new();
-        while let Some(packet) = self.try_drain_packet() {
-            result.push(packet);
+impl Packet {
+    /// Serializes a message with the given codec.
+    /// Fails if the packet would be too long or carry more file descriptors than can be transmitted.
+    pub fn encode<T: Serialize>(codec: &impl Codec, msg: &T, fds: Vec<OwnedFd>) -> Result<Packet, CodecError> {
+        let data = codec.encode(msg)?;
+        if data.len() > MAX_PAYLOAD_LEN {
+            return Err(CodecError::PayloadTooLarge { len: data.len(), max: MAX_PAYLOAD_LEN });
         }
-        result
+        let max = max_fds(data.len());
+        if fds.len() > max {
+            return Err(CodecError::TooManyFds { count: fds.len(), max });
+        }
+        Ok(Packet { data, kind: PacketKind::Raw, fds })
     }
 
-    fn new() -> PartialPacket {
-        PartialPacket {
-            data: Vec::new(),
-            fds: Vec::new(), 
-        }
+    /// Creates a packet of raw bytes, which does not hold a request or an event.
+    pub fn new(data: Vec<u8>, fds: Vec<OwnedFd>) -> Packet {
+        Packet { data, kind: PacketKind::Raw, fds }
     }
-}
 
-impl Packet {
-    // TODO: This leaks implementation details. The public API shouldn't expose bincode::Error.
-    // Also, I should consider using TryInto and TryFrom.
-    pub fn try_into_event(self) -> Result<(EventMsg, Vec<OwnedFd>), bincode::Error> {
-        let msg = bincode::deserialize(&self.data)?;
+    /// Serializes a message of the given kind with the codec that is used on the wire.
+    fn encode_as<T: Serialize>(kind: PacketKind, msg: &T, fds: Vec<OwnedFd>) -> Result<Packet, CodecError> {
+        let packet = Packet::encode(&BincodeCodec, msg, fds)?;
+        Ok(Packet { kind, ..packet })
+    }
+
+    /// Deserializes the packet with the codec that is used on the wire, if it has the expected kind.
+    fn decode_as<T: DeserializeOwned>(self, kind: PacketKind) -> Result<(T, Vec<OwnedFd>), CodecError> {
+        let msg = self.peek_as(kind)?;
         Ok((msg, self.fds))
     }
-    pub fn try_from_event(event: EventMsg, fds: Vec<OwnedFd>) -> Result<Packet, bincode::Error> {
-        let data = bincode::serialize(&event)?;
-        Ok(Packet { data, fds })
+
+    fn peek_as<T: DeserializeOwned>(&self, kind: PacketKind) -> Result<T, CodecError> {
+        if self.kind != kind {
+            return Err(CodecError::UnexpectedKind { expected: kind, found: self.kind });
+        }
+        self.peek(&BincodeCodec)
     }
 
-    pub fn try_into_request(self) -> Result<(RequestMsg, Vec<OwnedFd>), bincode::Error> {
-        let msg = bincode::deserialize(&self.data)?;
+    /// Deserializes the packet with the given codec.
+    ///
+    /// The packet gets consumed either way, so if decoding fails, the attached file descriptors get closed. Use
+    /// `peek` to hold on to them.
+    pub fn decode<T: DeserializeOwned>(self, codec: &impl Codec) -> Result<(T, Vec<OwnedFd>), CodecError> {
+        let msg = self.peek(codec)?;
         Ok((msg, self.fds))
     }
-    pub fn try_from_request(request: RequestMsg, fds: Vec<OwnedFd>) -> Result<Packet, bincode::Error> {
-        let data = bincode::serialize(&request)?;
-        Ok(Packet { data, fds })
+
+    /// Deserializes the packet with the given codec without consuming it. The file descriptors stay attached to
+    /// the packet, whether decoding succeeds or not, and get closed when the packet is dropped unless they are
+    /// taken out first.
+    pub fn peek<T: DeserializeOwned>(&self, codec: &impl Codec) -> Result<T, CodecError> {
+        codec.decode(&self.data)
+    }
+
+    /// Like `peek`, for packets that hold an event. Also fails if the event refers to file descriptors that the
+    /// packet does not carry.
+    pub fn peek_event(&self) -> Result<Event, crate::Error> {
+        Ok(self.peek_checked_event()?)
+    }
+
+    fn peek_checked_event(&self) -> Result<Event, CodecError> {
+        let event: Event = self.peek_as(PacketKind::Event)?;
+        event.msg.check_fd_indices(self.fds.len())?;
+        Ok(event)
+    }
+
+    /// Encodes an event after checking that it only refers to file descriptors that are attached.
+    fn encode_event(event: &Event, fds: Vec<OwnedFd>) -> Result<Packet, CodecError> {
+        event.msg.check_fd_indices(fds.len())?;
+        Packet::encode_as(PacketKind::Event, event, fds)
+    }
+
+    /// Like `peek`, for packets that hold a request.
+    pub fn peek_request(&self) -> Result<Request, crate::Error> {
+        Ok(self.peek_as(PacketKind::Request)?)
+    }
+
+    /// The length of the data, not counting the header that gets added when sending it.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// How many file descriptors are attached to the packet.
+    pub fn fd_count(&self) -> usize {
+        self.fds.len()
+    }
+
+    pub fn into_parts(self) -> (Vec<u8>, Vec<OwnedFd>) {
+        (self.data, self.fds)
+    }
+
+    #[deprecated(note = "Use the TryFrom<Packet> impl of (Event, Vec<OwnedFd>) instead.")]
+    pub fn try_into_event(self) -> Result<(Event, Vec<OwnedFd>), CodecError> {
+        let event = self.peek_checked_event()?;
+        Ok((event, self.fds))
+    }
+    #[deprecated(note = "Use the TryFrom<(Event, Vec<OwnedFd>)> impl of Packet instead.")]
+    pub fn try_from_event(event: Event, fds: Vec<OwnedFd>) -> Result<Packet, CodecError> {
+        Packet::encode_event(&event, fds)
+    }
+
+    #[deprecated(note = "Use the TryFrom<Packet> impl of (Request, Vec<OwnedFd>) instead.")]
+    pub fn try_into_request(self) -> Result<(Request, Vec<OwnedFd>), CodecError> {
+        self.decode_as(PacketKind::Request)
+    }
+    #[deprecated(note = "Use the TryFrom<(Request, Vec<OwnedFd>)> impl of Packet instead.")]
+    pub fn try_from_request(request: Request, fds: Vec<OwnedFd>) -> Result<Packet, CodecError> {
+        Packet::encode_as(PacketKind::Request, &request, fds)
+    }
+}
+
+/// Encodes an event with the codec that is used on the wire.
+impl TryFrom<(Event, Vec<OwnedFd>)> for Packet {
+    type Error = crate::Error;
+
+    fn try_from((event, fds): (Event, Vec<OwnedFd>)) -> Result<Packet, crate::Error> {
+        Ok(Packet::encode_event(&event, fds)?)
+    }
+}
+
+/// Decodes an event with the codec that is used on the wire. The file descriptors get closed if decoding fails;
+/// see `Packet::peek_event` for keeping them.
+impl TryFrom<Packet> for (Event, Vec<OwnedFd>) {
+    type Error = crate::Error;
+
+    fn try_from(packet: Packet) -> Result<Self, crate::Error> {
+        let event = packet.peek_event()?;
+        Ok((event, packet.fds))
+    }
+}
+
+/// Encodes a request with the codec that is used on the wire.
+impl TryFrom<(Request, Vec<OwnedFd>)> for Packet {
+    type Error = crate::Error;
+
+    fn try_from((request, fds): (Request, Vec<OwnedFd>)) -> Result<Packet, crate::Error> {
+        Ok(Packet::encode_as(PacketKind::Request, &request, fds)?)
+    }
+}
+
+/// Decodes a request with the codec that is used on the wire. The file descriptors get closed if decoding
+/// fails; see `Packet::peek_request` for keeping them.
+impl TryFrom<Packet> for (Request, Vec<OwnedFd>) {
+    type Error = crate::Error;
+
+    fn try_from(packet: Packet) -> Result<Self, crate::Error> {
+        Ok(packet.decode_as(PacketKind::Request)?)
     }
 }
 
@@ -108,41 +351,204 @@ pub struct Message<T> {
 pub struct StreamChannel {
     fd: OwnedFd,
     /// A partial packet containing data that has been read from the socket without having received end-of-message.
-    read_buffer: PartialPacket,
+    read_buffer: FrameReader,
+    /// The credentials that were most recently attached to a message received through this channel.
+    /// Only gets filled in after `peer_credentials()` has enabled SO_PASSCRED.
+    last_credentials: Option<Credentials>,
+    /// Packets that have been written to this channel, but could not be sent yet because the socket was full.
+    write_queue: VecDeque<OutgoingBatch>,
+    /// Scratch space for recvmsg. Kept around so it does not need to be zeroed again on every read.
+    receive_buffer: Box<[u8]>,
+    /// Scratch space for the control messages received by recvmsg, kept around for the same reason. Reading
+    /// requires `&mut self`, so neither buffer is ever used by two threads at once.
+    control_buffer: Box<[u8]>,
+}
+
+/// Identifies the process on the other side of a channel, as reported by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<rustix::net::UCred> for Credentials {
+    fn from(ucred: rustix::net::UCred) -> Self {
+        Credentials {
+            pid: ucred.pid.as_raw_nonzero().get(),
+            uid: ucred.uid.as_raw(),
+            gid: ucred.gid.as_raw(),
+        }
+    }
 }
 
 pub struct StreamSocket {
     fd: OwnedFd,
-    _path: UnlinkOnDrop,
+    /// The socket file, if the socket has one.
+    _path: Option<UnlinkOnDrop>,
 }
 
 impl StreamSocket {
     /// Creates a new socket that accepts incoming connections. Used by the server.
-    pub fn open(path: PathBuf) -> Result<StreamSocket, std::io::Error> {
-        // Create a socket FD.
-        let socket = rustix::net::socket(rustix::net::AddressFamily::UNIX, rustix::net::SocketType::STREAM, None)?;
+    ///
+    /// If a socket file already exists at the path, it gets replaced if nobody is listening on it anymore.
+    /// If another server is still listening on it, an error of kind `AddrInUse` is returned instead.
+    pub fn open(address: impl Into<Address>) -> Result<StreamSocket, std::io::Error> {
+        StreamSocketBuilder::new().open(address)
+    }
 
-        // Give the file descriptor the proper flags.
-        rustix::fs::fcntl_setfd(&socket, FdFlags::CLOEXEC)?;
-        rustix::fs::fcntl_setfl(&socket, OFlags::NONBLOCK)?;
+    /// Adopts the listening socket that systemd passed to us through socket activation. Returns None if we were
+    /// not socket-activated or if the passed file descriptor is not a listening Unix stream socket.
+    ///
+    /// The socket file belongs to systemd, so it does not get removed when the socket is dropped.
+    pub fn from_activation() -> Option<StreamSocket> {
+        let listen_pid = std::env::var_os("LISTEN_PID");
+        let listen_fds = std::env::var_os("LISTEN_FDS");
+        // Make sure that our children do not think the socket was meant for them.
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
 
-        // Bind the socket to the filesystem.
-        let socket_name = rustix::net::SocketAddrUnix::new(&path)?;
-        rustix::net::bind_unix(&socket, &socket_name)?;
+        StreamSocket::adopt_listen_fd(listen_pid, listen_fds, SD_LISTEN_FDS_START)
+    }
 
-        // Start listening to incoming connections.
-        let backlog_size = 32;
-        rustix::net::listen(&socket, backlog_size)?;
+    fn adopt_listen_fd(listen_pid: Option<OsString>, listen_fds: Option<OsString>, fd: RawFd) -> Option<StreamSocket> {
+        let listen_pid: u32 = listen_pid?.to_str()?.parse().ok()?;
+        if listen_pid != std::process::id() {
+            return None;
+        }
+        let listen_fds: u32 = listen_fds?.to_str()?.parse().ok()?;
+        match listen_fds {
+            0 => return None,
+            1 => (),
+            _ => log::warn!("Received {listen_fds} sockets through socket activation; only using the first one."),
+        }
 
-        Ok(StreamSocket {
-            fd: socket, _path: UnlinkOnDrop::new(path)
-        })
+        // SAFETY: systemd guarantees that the file descriptor is open. It does not get closed while borrowed.
+        let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+        let is_listening_unix_stream = rustix::net::sockopt::get_socket_domain(borrowed).ok() == Some(rustix::net::AddressFamily::UNIX)
+            && rustix::net::sockopt::get_socket_type(borrowed).ok() == Some(rustix::net::SocketType::STREAM)
+            && rustix::net::sockopt::get_socket_acceptconn(borrowed).unwrap_or(false);
+        if !is_listening_unix_stream {
+            log::warn!("The socket passed through socket activation is not a listening Unix stream socket.");
+            return None;
+        }
+
+        // SAFETY: the file descriptor was passed to this process to own, and we only adopt it once because the
+        // environment variables have been removed.
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        rustix::fs::fcntl_setfd(&socket, FdFlags::CLOEXEC).ok()?;
+        rustix::fs::fcntl_setfl(&socket, OFlags::NONBLOCK).ok()?;
+        Some(StreamSocket { fd: socket, _path: None })
     }
 
-    /// Receives a new incoming connection from a program.
-    pub fn accept(&self) -> Result<StreamChannel, std::io::Error> {
+    /// Receives a new incoming connection from a program. Also returns the credentials that the program had at
+    /// the time it connected.
+    pub fn accept(&self) -> Result<(StreamChannel, Credentials), std::io::Error> {
         let fd = rustix::net::accept_with(self, rustix::net::SocketFlags::NONBLOCK | rustix::net::SocketFlags::CLOEXEC)?;
-        Ok(StreamChannel { fd, read_buffer: PartialPacket::new() })
+        let credentials = rustix::net::sockopt::get_socket_peercred(&fd)?.into();
+        Ok((StreamChannel::from_fd(fd), credentials))
+    }
+}
+
+/// Configures how `StreamSocketBuilder::open` creates a socket. `StreamSocket::open` uses the defaults.
+///
+/// Whether the socket lives in the filesystem or in the abstract namespace is decided by the address passed
+/// to `open`.
+#[derive(Clone, Debug)]
+pub struct StreamSocketBuilder {
+    backlog: i32,
+    /// The permissions of the socket file, if they should not be left to the umask.
+    mode: Option<u32>,
+}
+
+impl Default for StreamSocketBuilder {
+    fn default() -> Self {
+        StreamSocketBuilder { backlog: 32, mode: None }
+    }
+}
+
+impl StreamSocketBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many connections may wait to be accepted before the kernel refuses new ones.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// Sets the permissions of the socket file, e.g. 0o600 so only the current user can connect. Connecting
+    /// requires write permission. Opening an address in the abstract namespace fails if a mode is set, because
+    /// there is no file to set it on.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Creates a new socket that accepts incoming connections.
+    ///
+    /// If a socket file already exists at the path, it gets replaced if nobody is listening on it anymore.
+    /// If another server is still listening on it, an error of kind `AddrInUse` is returned instead.
+    pub fn open(&self, address: impl Into<Address>) -> Result<StreamSocket, std::io::Error> {
+        let address = address.into();
+        if self.mode.is_some() && matches!(address, Address::Abstract(_)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Sockets in the abstract namespace have no file whose mode could be set.",
+            ));
+        }
+
+        // Create a socket FD. The flags are set right away, so the socket cannot leak into a process that
+        // another thread spawns in the meantime.
+        let socket = rustix::net::socket_with(
+            rustix::net::AddressFamily::UNIX,
+            rustix::net::SocketType::STREAM,
+            rustix::net::SocketFlags::CLOEXEC | rustix::net::SocketFlags::NONBLOCK,
+            None,
+        )?;
+
+        // Bind the socket to the filesystem or the abstract namespace.
+        let socket_name = address.to_socket_addr()?;
+        match rustix::net::bind_unix(&socket, &socket_name) {
+            Ok(()) => (),
+            Err(rustix::io::Errno::ADDRINUSE) => {
+                let already_running = || std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("UIO server already running at {address}"),
+                );
+                // Abstract names disappear together with their socket, so they are never stale.
+                let Address::Path(path) = &address else { return Err(already_running()) };
+                match StreamChannel::open(path) {
+                    Ok(_) => return Err(already_running()),
+                    // The file is a leftover of a server that did not shut down cleanly.
+                    Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                        std::fs::remove_file(path)?;
+                        rustix::net::bind_unix(&socket, &socket_name)?;
+                    },
+                    Err(_) => return Err(rustix::io::Errno::ADDRINUSE.into()),
+                }
+            },
+            Err(err) => return Err(err.into()),
+        }
+
+        let path = match address {
+            Address::Path(path) => Some(UnlinkOnDrop::new(path)),
+            Address::Abstract(_) => None,
+        };
+
+        // Nobody can connect before we listen, so there is no window in which the file has the wrong mode.
+        if let (Some(mode), Some(path)) = (self.mode, &path) {
+            rustix::fs::chmod(path.path(), Mode::from_raw_mode(mode))?;
+        }
+
+        // Start listening to incoming connections.
+        rustix::net::listen(&socket, self.backlog)?;
+
+        Ok(StreamSocket {
+            fd: socket, _path: path
+        })
     }
 }
 
@@ -154,65 +560,146 @@ impl std::os::fd::AsFd for StreamSocket {
 
 impl StreamChannel {
     /// Connects to an already existing socket. Used by the client.
-    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
-        // Create a socket FD.
-        let socket = rustix::net::socket(rustix::net::AddressFamily::UNIX, rustix::net::SocketType::STREAM, None)?;
-
-        // Give the file descriptor the proper flags.
-        rustix::fs::fcntl_setfd(&socket, FdFlags::CLOEXEC)?;
-        rustix::fs::fcntl_setfl(&socket, OFlags::NONBLOCK)?;
+    pub fn open(address: impl Into<Address>) -> Result<Self, std::io::Error> {
+        // Create a socket FD. The flags are set right away, so the socket cannot leak into a process that
+        // another thread spawns in the meantime.
+        let socket = rustix::net::socket_with(
+            rustix::net::AddressFamily::UNIX,
+            rustix::net::SocketType::STREAM,
+            rustix::net::SocketFlags::CLOEXEC | rustix::net::SocketFlags::NONBLOCK,
+            None,
+        )?;
         
-        // Open the socket from the filesystem.
-        let socket_name = rustix::net::SocketAddrUnix::new(path)?;
+        // Open the socket from the filesystem or the abstract namespace.
+        let socket_name = address.into().to_socket_addr()?;
         rustix::net::connect_unix(&socket, &socket_name)?;
 
-        Ok(StreamChannel {
-            fd: socket, read_buffer: PartialPacket::new()
-        })
+        Ok(StreamChannel::from_fd(socket))
     }
 
-    pub fn read_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
-        const MSG_BUF_SIZE: usize = 16 * 1024;
+    /// Creates two nonblocking channels that are connected to each other, without a socket on the filesystem.
+    /// Useful for tests, and for talking to a child process that inherits one of the ends.
+    pub fn pair() -> Result<(StreamChannel, StreamChannel), std::io::Error> {
+        let (left, right) = rustix::net::socketpair(
+            rustix::net::AddressFamily::UNIX,
+            rustix::net::SocketType::STREAM,
+            rustix::net::SocketFlags::CLOEXEC | rustix::net::SocketFlags::NONBLOCK,
+            None,
+        )?;
+        Ok((StreamChannel::from_fd(left), StreamChannel::from_fd(right)))
+    }
 
-        // ... I'm not a fan of how rustix requires us to zero-init the whole buffer, but then again, I have
-        // better things to do right now than micro-optimizations.
-        let mut msg_buf: [u8; MSG_BUF_SIZE] = [0; MSG_BUF_SIZE];
-        let mut control_space = [0; rustix::cmsg_space!(ScmRights(32))];
+    /// Like `open()`, but if the server is not up yet, keeps trying to connect with increasing intervals until
+    /// the timeout elapses. Returns an error of kind `TimedOut` if the server did not come up in time.
+    pub fn open_with_retry(path: &Path, timeout: Duration) -> Result<Self, std::io::Error> {
+        const INITIAL_DELAY: Duration = Duration::from_millis(10);
+        const MAX_DELAY: Duration = Duration::from_millis(500);
 
-        let mut iovec = libc::iovec {
-            iov_base: &mut msg_buf as *mut _ as *mut libc::c_void,
-            iov_len: std::mem::size_of_val(&msg_buf),
-        };
+        let deadline = Instant::now() + timeout;
+        let mut delay = INITIAL_DELAY;
+        loop {
+            let err = match StreamChannel::open(path) {
+                Ok(channel) => return Ok(channel),
+                Err(err) => err,
+            };
 
-        let mut msghdr = libc::msghdr {
-            msg_name: std::ptr::null_mut(),
-            msg_namelen: 0,
-            msg_iov: &mut iovec as *mut _,
-            // msg_iovlen: the amount of iovecs you pass. In this case, we pass only a single iovec.
-            // It is NOT the amount of bytes those iovecs occupy.
-            msg_iovlen: 1,
-            msg_control: &mut control_space as *mut _ as *mut libc::c_void,
-            msg_controllen: std::mem::size_of_val(&control_space),
-            msg_flags: 0,
-        };
+            if !server_may_come_up(&err) {
+                return Err(err);
+            }
 
-        let num_bytes = unsafe { libc::recvmsg(
-            self.fd.as_raw_fd(),
-            &mut msghdr,
-            libc::MSG_CMSG_CLOEXEC
-        )};
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("Timed out while trying to connect to {}: {err}", path.display()),
+                ));
+            }
 
-        if num_bytes < 0 {
-            return Err(std::io::Error::last_os_error());
+            std::thread::sleep(delay.min(deadline - now));
+            delay = (delay * 2).min(MAX_DELAY);
         }
-        let bytes = num_bytes as usize;
+    }
 
-        let mut control_buf = RecvAncillaryBuffer::new(&mut control_space);
-        let flags = msghdr.msg_flags;
+    fn from_fd(fd: OwnedFd) -> Self {
+        StreamChannel {
+            fd, read_buffer: FrameReader::new(), last_credentials: None, write_queue: VecDeque::new(),
+            receive_buffer: vec![0; RECEIVE_BUFFER_SIZE].into_boxed_slice(),
+            control_buffer: vec![0; CONTROL_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Returns the credentials of the process on the other side of this channel, as they were at the time the
+    /// connection was made. Also enables SO_PASSCRED, so that the kernel attaches the current credentials of the
+    /// peer to all subsequent messages; those can be retrieved with `last_credentials()`.
+    pub fn peer_credentials(&self) -> Result<Credentials, std::io::Error> {
+        rustix::net::sockopt::set_socket_passcred(&self.fd, true)?;
+        let ucred = rustix::net::sockopt::get_socket_peercred(&self.fd)?;
+        Ok(ucred.into())
+    }
 
-        // TODO: This can cause out-of-memory when dealing with a malicious client.
-        let message = &msg_buf[0 .. bytes];
-        self.read_buffer.data.extend_from_slice(message);
+    /// The address of this end of the channel. For a channel accepted by a server, that is the address of the
+    /// server's socket. None if this end is unnamed.
+    pub fn local_addr(&self) -> Result<Option<Address>, std::io::Error> {
+        Ok(Address::from_socket_addr(Some(rustix::net::getsockname(&self.fd)?)))
+    }
+
+    /// The address of the other end of the channel. None if that end is unnamed, which it usually is for the
+    /// clients of a server.
+    pub fn peer_addr(&self) -> Result<Option<Address>, std::io::Error> {
+        Ok(Address::from_socket_addr(rustix::net::getpeername(&self.fd)?))
+    }
+
+    /// The credentials attached to the most recently received message, if any.
+    pub fn last_credentials(&self) -> Option<Credentials> {
+        self.last_credentials
+    }
+
+    /// Reads from the socket and returns all packets that are complete. Returns an empty Vec if there was
+    /// nothing to read, or an error of kind `ConnectionReset` if the peer has closed its end of the channel.
+    pub fn read_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
+        Ok(self.read_packets_iter()?.collect())
+    }
+
+    /// Reads from the socket and returns an iterator over the complete packets, without collecting them.
+    pub fn read_packets_iter(&mut self) -> Result<Packets<'_>, std::io::Error> {
+        self.receive()?;
+        Ok(self.read_buffer.drain())
+    }
+
+    /// Keeps reading from the socket until it has nothing more to offer, then returns all packets that are
+    /// complete. Meant for channels registered with an edge-triggered epoll: `read_packets` performs a single
+    /// read, so it can return no packets while part of a big packet is still waiting in the socket, and that
+    /// part will not cause another wakeup. Must not be used on blocking channels, because the last read would
+    /// block.
+    pub fn read_all_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
+        while self.receive()? {}
+        Ok(self.read_buffer.drain().collect())
+    }
+
+    /// Performs a single read from the socket and appends the result to the read buffer. Returns false if the
+    /// socket had nothing to read.
+    fn receive(&mut self) -> Result<bool, std::io::Error> {
+
+        // The ancillary data must be parsed by the same RecvAncillaryBuffer that was passed to recvmsg, because
+        // only that one knows how many bytes of control data the kernel has written.
+        let mut control_buf = RecvAncillaryBuffer::new(&mut self.control_buffer);
+        let result = match retry_on_interrupt(|| rustix::net::recvmsg(
+            &self.fd,
+            &mut [IoSliceMut::new(&mut self.receive_buffer)],
+            &mut control_buf,
+            RecvFlags::CMSG_CLOEXEC,
+        )) {
+            Ok(result) => result,
+            // The socket is nonblocking, so we may get woken up while there is nothing to read. That is not an
+            // error; the caller will simply find no new packets.
+            Err(rustix::io::Errno::AGAIN) => return Ok(false),
+            Err(err) => return Err(err.into()),
+        };
+        let bytes = result.bytes;
+        let flags = result.flags.bits() as i32;
+        if bytes == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "The peer closed the channel."));
+        }
 
         // TODO: In production code, all of the following instances of panic! are obviously unacceptable.
         if flags & libc::MSG_TRUNC > 0 {
@@ -222,60 +709,1122 @@ impl StreamChannel {
             panic!("Received error message through socket!");
         }
         if flags & libc::MSG_CTRUNC > 0 {
-            panic!("Part of control data was discarded!");
+            // The kernel has closed the file descriptors that did not fit, so the packets they belonged to
+            // can no longer be reconstructed.
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData, "Received more file descriptors than fit in the control buffer."
+            ));
         }
 
+        let mut fds = Vec::new();
         for control_msg in control_buf.drain() {
             match control_msg {
-                RecvAncillaryMessage::ScmRights(fds) => self.read_buffer.fds.extend(fds),
-                RecvAncillaryMessage::ScmCredentials(_) => panic!("Received credentials!"),
+                RecvAncillaryMessage::ScmRights(received) => fds.extend(received),
+                RecvAncillaryMessage::ScmCredentials(ucred) => self.last_credentials = Some(ucred.into()),
                 _ => panic!("Received unknown ancillary data!"),
             }
         }
+        self.read_buffer.feed(&self.receive_buffer[0 .. bytes], fds)?;
 
-        println!("Received bytes: {}, received flags: {:x}", bytes, flags);
-        
-        return Ok(self.read_buffer.drain_packets())
+        log::trace!("Received bytes: {}, received flags: {:x}", bytes, flags);
+
+        Ok(true)
     }
 
     pub fn write_packet(&mut self, packet: Packet) -> Result<(), std::io::Error> {
-        // Add the header to the packet for transmission.
-        let mut data_with_header = Vec::with_capacity(packet.data.len() + PACKET_HEADER_LEN);
-        data_with_header.extend_from_slice(&u16::to_le_bytes(packet.data.len().try_into().expect("Packet is too big!")));
-        data_with_header.extend_from_slice(&u16::to_le_bytes(packet.fds.len().try_into().expect("Packet has too many file descriptors!")));
-        data_with_header.extend_from_slice(&packet.data);
+        self.write_packets(vec![packet])
+    }
+
+    /// Writes several packets using as few syscalls as possible. Consecutive packets get sent in a single
+    /// syscall as long as their file descriptors fit in a single control message and their combined size
+    /// stays within a reasonable limit.
+    ///
+    /// Packets with more file descriptors than fit in a single control message get split over several
+    /// syscalls. Each of those syscalls must carry at least one byte of the packet, so such packets are
+    /// rejected if they are too short to carry all of their file descriptors.
+    pub fn write_packets(&mut self, packets: Vec<Packet>) -> Result<(), std::io::Error> {
+        self.queue_packets(packets)?;
+        self.flush()
+    }
 
+    /// Like `write_packets`, but only queues the packets without trying to send them. They get sent by the next
+    /// call to `flush()` or any of the write functions. Useful when the socket is known to be full, to avoid a
+    /// syscall that would fail anyway.
+    pub fn queue_packets(&mut self, packets: Vec<Packet>) -> Result<(), std::io::Error> {
+        for packet in &packets {
+            if packet.data.len() > MAX_PAYLOAD_LEN {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    CodecError::PayloadTooLarge { len: packet.data.len(), max: MAX_PAYLOAD_LEN },
+                ));
+            }
+            let max = max_fds(packet.data.len());
+            if packet.fds.len() > max {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    CodecError::TooManyFds { count: packet.fds.len(), max },
+                ));
+            }
+        }
+
+        let mut batch = OutgoingBatch::new();
+
+        for packet in packets {
+            #[cfg(feature = "wire-dump")]
+            log::debug!("{}", dump_packet("Sending", &packet));
+
+            let packet_len = packet.data.len() + PACKET_HEADER_LEN;
+            if packet.fds.len() > MAX_FDS_PER_SYSCALL {
+                if !batch.data.is_empty() {
+                    self.write_queue.push_back(std::mem::replace(&mut batch, OutgoingBatch::new()));
+                }
+                self.queue_split_packet(packet);
+                continue;
+            }
+
+            let batch_is_full = batch.fds.len() + packet.fds.len() > MAX_FDS_PER_SYSCALL
+                || batch.data.len() + packet_len > MAX_BYTES_PER_SYSCALL;
+            if !batch.data.is_empty() && batch_is_full {
+                self.write_queue.push_back(std::mem::replace(&mut batch, OutgoingBatch::new()));
+            }
+
+            append_framed(&mut batch.data, &packet);
+            batch.fds.extend(packet.fds);
+        }
+
+        if !batch.data.is_empty() {
+            self.write_queue.push_back(batch);
+        }
+
+        Ok(())
+    }
+
+    /// Queues a packet that has too many file descriptors for a single syscall. Every batch but the last
+    /// carries a single byte of the packet; the last one carries the rest.
+    fn queue_split_packet(&mut self, packet: Packet) {
+        let mut data = Vec::new();
+        append_framed(&mut data, &packet);
+        let mut fds = packet.fds.into_iter();
+
+        let num_syscalls = fds.len().div_ceil(MAX_FDS_PER_SYSCALL);
+        for _ in 1 .. num_syscalls {
+            let rest = data.split_off(1);
+            let fds = fds.by_ref().take(MAX_FDS_PER_SYSCALL).collect();
+            self.write_queue.push_back(OutgoingBatch { data, sent: 0, fds });
+            data = rest;
+        }
+        self.write_queue.push_back(OutgoingBatch { data, sent: 0, fds: fds.collect() });
+    }
+
+    /// Tries to send all packets that have been written to this channel but have not been sent yet. If the
+    /// socket cannot take any more data, the remaining packets stay queued until the next call to `flush()`.
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        while let Some(batch) = self.write_queue.front_mut() {
+            match batch.send(self.fd.as_fd()) {
+                Ok(()) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            if batch.is_sent() {
+                self.write_queue.pop_front();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether some written packets have not been sent yet because the socket could not take more data.
+    pub fn has_pending_writes(&self) -> bool {
+        !self.write_queue.is_empty()
+    }
+
+    /// How many bytes of written packets have not been sent yet, including their headers.
+    pub fn queued_bytes(&self) -> usize {
+        self.write_queue.iter().map(|batch| batch.data.len() - batch.sent).sum()
+    }
+
+    /// Keeps sending queued packets until none are left, waiting for the socket to become writable in between.
+    /// Fails with an error of kind `TimedOut` if packets are still queued once the timeout has passed.
+    pub fn flush_blocking(&mut self, timeout: Duration) -> Result<(), std::io::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.flush()?;
+            if !self.has_pending_writes() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("The peer did not take the last {} queued bytes in time.", self.queued_bytes()),
+                ));
+            }
+            let timeout_ms = (deadline - now).as_millis().max(1).min(i32::MAX as u128) as i32;
+            let mut to_poll = [PollFd::new(&self.fd, PollFlags::OUT)];
+            retry_on_interrupt(|| rustix::event::poll(&mut to_poll, timeout_ms))?;
+        }
+    }
+
+    /// Throws away the packets that have not been sent yet and returns how many bytes they had. Dropping the
+    /// channel afterwards does not wait for anything.
+    pub fn discard_pending_writes(&mut self) -> usize {
+        let discarded = self.queued_bytes();
+        self.write_queue.clear();
+        discarded
+    }
+
+    /// Sends whatever is still queued and closes the channel. Unlike dropping the channel, which does the same,
+    /// this reports whether everything got sent.
+    pub fn close(mut self, timeout: Duration) -> Result<(), std::io::Error> {
+        let result = self.flush_blocking(timeout);
+        self.discard_pending_writes();
+        result
+    }
+}
+
+impl Channel for StreamChannel {
+    fn read_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
+        StreamChannel::read_packets(self)
+    }
+
+    fn write_packet(&mut self, packet: Packet) -> Result<(), std::io::Error> {
+        StreamChannel::write_packet(self, packet)
+    }
+
+    fn queued_bytes(&self) -> usize {
+        StreamChannel::queued_bytes(self)
+    }
+}
+
+/// How many file descriptors a packet with `data_len` bytes of data can carry. Packets with more file descriptors
+/// than fit in a single syscall need at least one byte of the framed packet for every syscall.
+fn max_fds(data_len: usize) -> usize {
+    ((data_len + PACKET_HEADER_LEN) * MAX_FDS_PER_SYSCALL).min(MAX_FDS_PER_PACKET)
+}
+
+/// Framed packets that are to be sent over the socket in a single syscall, if the socket can take them.
+struct OutgoingBatch {
+    data: Vec<u8>,
+    /// How many bytes of `data` have already been sent.
+    sent: usize,
+    /// The file descriptors that get sent along with the first byte of `data`.
+    fds: Vec<OwnedFd>,
+}
+
+impl OutgoingBatch {
+    fn new() -> Self {
+        OutgoingBatch { data: Vec::new(), sent: 0, fds: Vec::new() }
+    }
+
+    fn is_sent(&self) -> bool {
+        self.sent == self.data.len()
+    }
+
+    /// Sends as much of the remaining data as the socket takes in a single syscall.
+    fn send(&mut self, socket: BorrowedFd) -> Result<(), std::io::Error> {
         // Put the data in a format that libc expects.
-        let slice = [IoSlice::new(&data_with_header)];
-        let mut control_space = [0; rustix::cmsg_space!(ScmRights(32))];
+        let slice = [IoSlice::new(&self.data[self.sent ..])];
+        let mut control_space = [0; rustix::cmsg_space!(ScmRights(MAX_FDS_PER_SYSCALL))];
         let mut control_buf = SendAncillaryBuffer::new(&mut control_space);
-        let rights: Vec<BorrowedFd> = packet.fds.iter().map(|fd| fd.as_fd()).collect();
-        let res = control_buf.push(SendAncillaryMessage::ScmRights(&rights));
-        if !res {
-            panic!("Failed to send file descriptors.")
+        let rights: Vec<BorrowedFd> = self.fds.iter().map(|fd| fd.as_fd()).collect();
+        if !rights.is_empty() {
+            let res = control_buf.push(SendAncillaryMessage::ScmRights(&rights));
+            if !res {
+                panic!("Failed to send file descriptors.")
+            }
         }
 
         // Send the data.
-        let num_sent_bytes = rustix::net::sendmsg(
-            &self,
+        let num_sent_bytes = retry_on_interrupt(|| rustix::net::sendmsg(
+            socket,
             &slice,
             &mut control_buf,
-            SendFlags::empty()
-        )?;
+            // Writing to a closed channel should return an error rather than raise SIGPIPE.
+            SendFlags::NOSIGNAL
+        ))?;
 
-        // It is possible that not all data is transmitted in a single call. Or even any amount of calls, in case the receiving
-        // buffer is full. We need to think about how to handle that situation in the release version, but for experiment we just
-        // panic if anything looks remotely funny.
-        if num_sent_bytes != data_with_header.len() {
-            panic!("Failed to transmit a packet within a single syscall!");
-        }
+        // It is possible that not all data is transmitted in a single call. The file descriptors are attached to
+        // the first byte that was sent, so they must not be sent again with the rest of the data.
+        self.sent += num_sent_bytes;
+        self.fds.clear();
 
         Ok(())
     }
 }
 
+/// Whether a failure to connect may go away by itself. ENOENT means the server has not created its socket yet,
+/// ECONNREFUSED means that the socket exists but nobody is listening on it, e.g. because it is a leftover of a
+/// server that has since stopped.
+pub(crate) fn server_may_come_up(err: &std::io::Error) -> bool {
+    matches!(err.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused)
+}
+
+/// Repeats a syscall for as long as it gets interrupted by a signal handler.
+pub(crate) fn retry_on_interrupt<T>(mut syscall: impl FnMut() -> rustix::io::Result<T>) -> rustix::io::Result<T> {
+    loop {
+        match syscall() {
+            Err(rustix::io::Errno::INTR) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Gives queued packets a last chance to be sent, so that dropping a channel right after writing to it does not
+/// lose them. Use `close` or `discard_pending_writes` to decide what happens to them instead.
+impl Drop for StreamChannel {
+    fn drop(&mut self) {
+        if !self.has_pending_writes() {
+            return;
+        }
+        if let Err(err) = self.flush_blocking(DROP_FLUSH_TIMEOUT) {
+            log::warn!("Closing a channel with unsent packets: {err}");
+        }
+    }
+}
+
 impl std::os::fd::AsFd for StreamChannel {
     fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
         self.fd.as_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::thread::JoinHandleExt;
+
+    use rustix::net::{AddressFamily, SocketFlags, SocketType};
+
+    use super::*;
+    use crate::message::{EventMsg, HelloMsg, RequestMsg};
+    #[cfg(feature = "checksum")]
+    use crate::framing::crc32;
+    use crate::framing::MAX_BUFFERED_FDS;
+    use crate::test_utils::unique_socket_path;
+
+    /// Creates two connected channels in blocking mode, so big packets can be written without the test
+    /// having to deal with partial writes.
+    fn blocking_pair() -> (StreamChannel, StreamChannel) {
+        let (left, right) = rustix::net::socketpair(
+            AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC, None
+        ).unwrap();
+        (StreamChannel::from_fd(left), StreamChannel::from_fd(right))
+    }
+
+    /// Keeps reading from the channel until at least one packet has been received.
+    fn read_until_packets(channel: &mut StreamChannel) -> Vec<Packet> {
+        loop {
+            let packets = channel.read_packets().unwrap();
+            if !packets.is_empty() {
+                return packets;
+            }
+        }
+    }
+
+    #[test]
+    fn packet_accessors() {
+        let packet = Packet::new(vec![1, 2, 3], vec![dev_null()]);
+        assert_eq!(packet.len(), 3);
+        assert!(!packet.is_empty());
+        assert_eq!(packet.fd_count(), 1);
+        #[cfg(not(feature = "wire-dump"))]
+        assert_eq!(format!("{packet:?}"), "Packet { kind: Raw, len: 3, fd_count: 1 }");
+        #[cfg(feature = "wire-dump")]
+        assert_eq!(format!("{packet:?}"), "Packet { kind: Raw, len: 3, fd_count: 1, data: [1, 2, 3] }");
+
+        let (data, fds) = packet.into_parts();
+        assert_eq!(data, vec![1, 2, 3]);
+        assert_eq!(fds.len(), 1);
+
+        assert!(Packet::new(Vec::new(), Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn try_into_round_trip() {
+        let request = Request { serial: 5, msg: RequestMsg::Pong { nonce: 9 } };
+        let packet: Packet = (request, vec![dev_null()]).try_into().unwrap();
+        let (request, fds): (Request, Vec<OwnedFd>) = packet.try_into().unwrap();
+        assert!(matches!(request, Request { serial: 5, msg: RequestMsg::Pong { nonce: 9 } }));
+        assert_eq!(fds.len(), 1);
+
+        let event = Event { serial: None, msg: EventMsg::Shutdown };
+        let packet: Packet = (event, Vec::new()).try_into().unwrap();
+        let (event, fds): (Event, Vec<OwnedFd>) = packet.try_into().unwrap();
+        assert!(matches!(event, Event { serial: None, msg: EventMsg::Shutdown }));
+        assert!(fds.is_empty());
+    }
+
+    #[test]
+    fn try_into_reports_codec_errors() {
+        let garbage = Packet { data: vec![0xff; 3], kind: PacketKind::Event, fds: Vec::new() };
+        let result: Result<(Event, Vec<OwnedFd>), crate::Error> = garbage.try_into();
+        assert!(matches!(result, Err(crate::Error::Codec(CodecError::Bincode(_)))));
+        let err = std::io::Error::from(result.unwrap_err());
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    /// A memfd holding the given contents, so it can be told apart from other file descriptors after a trip
+    /// through a channel.
+    fn memfd_with(contents: &[u8]) -> OwnedFd {
+        let fd = rustix::fs::memfd_create("test", rustix::fs::MemfdFlags::CLOEXEC).unwrap();
+        rustix::io::write(&fd, contents).unwrap();
+        fd
+    }
+
+    fn read_memfd(fd: &OwnedFd) -> Vec<u8> {
+        let mut contents = vec![0; 16];
+        let len = rustix::io::pread(fd, &mut contents, 0).unwrap();
+        contents.truncate(len);
+        contents
+    }
+
+    #[test]
+    fn surface_update_keeps_the_roles_of_its_fds() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let event = Event { serial: None, msg: EventMsg::SurfaceUpdate { buffer_index: 1, fence_index: 0 } };
+        sender.send_event(event, vec![memfd_with(b"fence"), memfd_with(b"buffer")]).unwrap();
+
+        let (event, fds): (Event, Vec<OwnedFd>) = read_until_packets(&mut receiver).pop().unwrap().try_into().unwrap();
+        let EventMsg::SurfaceUpdate { buffer_index, fence_index } = event.msg else { panic!("Got {event:?}") };
+        assert_eq!(read_memfd(&fds[buffer_index as usize]), b"buffer");
+        assert_eq!(read_memfd(&fds[fence_index as usize]), b"fence");
+    }
+
+    #[test]
+    fn accepted_channel_reports_the_address_of_the_socket() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let client = StreamChannel::open(&path).unwrap();
+        let (accepted, _) = socket.accept().unwrap();
+
+        assert_eq!(accepted.local_addr().unwrap(), Some(Address::Path(path.clone())));
+        assert_eq!(accepted.peer_addr().unwrap(), None);
+        assert_eq!(client.local_addr().unwrap(), None);
+        assert_eq!(client.peer_addr().unwrap(), Some(Address::Path(path)));
+
+        let name = format!("uio-test-{}-address", std::process::id()).into_bytes();
+        let socket = StreamSocket::open(Address::Abstract(name.clone())).unwrap();
+        let _client = StreamChannel::open(Address::Abstract(name.clone())).unwrap();
+        let (accepted, _) = socket.accept().unwrap();
+        assert_eq!(accepted.local_addr().unwrap(), Some(Address::Abstract(name)));
+
+        let (left, _) = StreamChannel::pair().unwrap();
+        assert_eq!(left.local_addr().unwrap(), None);
+        assert_eq!(left.peer_addr().unwrap(), None);
+    }
+
+    #[test]
+    fn pair_is_connected_both_ways() {
+        let (mut left, mut right) = StreamChannel::pair().unwrap();
+        left.write_packet(Packet::new(vec![1, 2], vec![memfd_with(b"left")])).unwrap();
+        right.write_packet(Packet::new(vec![3], Vec::new())).unwrap();
+
+        let [packet] = &right.read_packets().unwrap()[..] else { panic!("Expected a single packet.") };
+        assert_eq!(packet.data, vec![1, 2]);
+        assert_eq!(read_memfd(&packet.fds[0]), b"left");
+        assert_eq!(left.read_packets().unwrap()[0].data, vec![3]);
+    }
+
+    #[test]
+    fn dropping_a_channel_sends_what_is_queued() {
+        let (mut sender, mut receiver) = StreamChannel::pair().unwrap();
+        sender.write_packet(Packet::new(vec![9; 1024 * 1024], Vec::new())).unwrap();
+        assert!(sender.has_pending_writes());
+
+        let reader = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            loop {
+                let mut to_poll = [PollFd::new(&receiver, PollFlags::IN)];
+                rustix::event::poll(&mut to_poll, -1).unwrap();
+                match receiver.read_packets() {
+                    Ok(packets) => received.extend(packets),
+                    Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => return received,
+                    Err(err) => panic!("{err}"),
+                }
+            }
+        });
+        drop(sender);
+
+        let received = reader.join().unwrap();
+        assert_eq!(received.iter().map(Packet::len).collect::<Vec<_>>(), [1024 * 1024]);
+    }
+
+    #[test]
+    fn close_reports_a_peer_that_does_not_read() {
+        let (mut sender, _receiver) = StreamChannel::pair().unwrap();
+        sender.write_packet(Packet::new(vec![9; 1024 * 1024], Vec::new())).unwrap();
+
+        let err = sender.close(Duration::from_millis(20)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn fd_indices_beyond_the_attached_fds_are_rejected() {
+        let msg = EventMsg::SurfaceUpdate { buffer_index: 0, fence_index: 1 };
+        let result = Packet::try_from((Event { serial: None, msg }, vec![dev_null()]));
+        assert!(matches!(result, Err(crate::Error::Codec(CodecError::FdIndexOutOfRange { index: 1, fd_count: 1 }))));
+
+        // A peer that does not check what it sends.
+        let msg = EventMsg::SurfaceUpdate { buffer_index: 2, fence_index: 0 };
+        let mut packet = Packet::encode(&BincodeCodec, &Event { serial: None, msg }, vec![dev_null(), dev_null()]).unwrap();
+        packet.kind = PacketKind::Event;
+        assert!(packet.peek_event().is_err());
+        let result: Result<(Event, Vec<OwnedFd>), crate::Error> = packet.try_into();
+        assert!(matches!(result, Err(crate::Error::Codec(CodecError::FdIndexOutOfRange { index: 2, fd_count: 2 }))));
+    }
+
+    #[test]
+    fn fds_survive_a_failed_peek() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let corrupt = Packet { data: vec![0xff; 3], kind: PacketKind::Request, fds: vec![dev_null(), dev_null()] };
+        sender.write_packet(corrupt).unwrap();
+
+        let mut packet = read_until_packets(&mut receiver).pop().unwrap();
+        assert!(packet.peek_request().is_err());
+        assert_eq!(packet.fd_count(), 2);
+        for fd in std::mem::take(&mut packet.fds) {
+            assert!(rustix::fs::fstat(&fd).is_ok());
+        }
+    }
+
+    #[test]
+    fn mistagged_packet_is_rejected() {
+        let event = Event { serial: None, msg: EventMsg::Shutdown };
+        let packet: Packet = (event, Vec::new()).try_into().unwrap();
+        assert_eq!(packet.kind, PacketKind::Event);
+
+        let result: Result<(Request, Vec<OwnedFd>), crate::Error> = packet.try_into();
+        assert!(matches!(result, Err(crate::Error::Codec(CodecError::UnexpectedKind {
+            expected: PacketKind::Request, found: PacketKind::Event,
+        }))));
+    }
+
+    #[test]
+    fn kind_survives_the_wire() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let request = Request { serial: 1, msg: RequestMsg::ListClients };
+        sender.write_packet((request, Vec::new()).try_into().unwrap()).unwrap();
+        sender.write_packet(Packet::new(vec![1], Vec::new())).unwrap();
+
+        let mut packets = Vec::new();
+        while packets.len() < 2 {
+            packets.extend(read_until_packets(&mut receiver));
+        }
+        assert_eq!(packets[0].kind, PacketKind::Request);
+        assert_eq!(packets[1].kind, PacketKind::Raw);
+    }
+
+    #[test]
+    fn header_with_unknown_kind_is_rejected() {
+        let (sender, mut receiver) = blocking_pair();
+        let mut header = u32::to_le_bytes(0).to_vec();
+        header.extend_from_slice(&[0, 0, 0xff]);
+        header.resize(PACKET_HEADER_LEN, 0);
+        rustix::io::write(&sender.fd, &header).unwrap();
+
+        let Err(err) = receiver.read_packets() else { panic!("Reading a packet of an unknown kind succeeded.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn big_packet_round_trip() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let payload: Vec<u8> = (0 .. 200 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let expected = payload.clone();
+        let writer = std::thread::spawn(move || {
+            sender.write_packet(Packet::new(payload, Vec::new())).unwrap();
+        });
+
+        let packets = read_until_packets(&mut receiver);
+        writer.join().unwrap();
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, expected);
+    }
+
+    #[test]
+    fn packet_of_maximum_length_round_trip() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let writer = std::thread::spawn(move || {
+            sender.write_packet(Packet::new(vec![7; MAX_PAYLOAD_LEN], Vec::new())).unwrap();
+        });
+
+        let packets = read_until_packets(&mut receiver);
+        writer.join().unwrap();
+
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data.len(), MAX_PAYLOAD_LEN);
+    }
+
+    #[test]
+    fn packet_beyond_maximum_length_is_rejected() {
+        let (mut sender, _receiver) = blocking_pair();
+        let err = sender.write_packet(Packet::new(vec![7; MAX_PAYLOAD_LEN + 1], Vec::new())).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Bincode prefixes the bytes with their length, which pushes the payload over the limit.
+        let Err(err) = Packet::encode(&BincodeCodec, &vec![7u8; MAX_PAYLOAD_LEN], Vec::new()) else {
+            panic!("Encoding a too long payload succeeded.")
+        };
+        assert!(matches!(err, CodecError::PayloadTooLarge { max: MAX_PAYLOAD_LEN, .. }));
+    }
+
+    #[test]
+    fn announced_length_beyond_maximum_is_rejected() {
+        let (sender, mut receiver) = blocking_pair();
+        let mut header = u32::to_le_bytes(MAX_PAYLOAD_LEN as u32 + 1).to_vec();
+        header.extend_from_slice(&[0, 0, PacketKind::Raw as u8]);
+        header.resize(PACKET_HEADER_LEN, 0);
+        rustix::io::write(&sender.fd, &header).unwrap();
+
+        let Err(err) = receiver.read_packets() else { panic!("Reading a too long packet succeeded.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reused_receive_buffer_does_not_leak_stale_bytes() {
+        let (mut sender, mut receiver) = blocking_pair();
+        sender.write_packet(Packet::new(vec![0xaa; RECEIVE_BUFFER_SIZE - PACKET_HEADER_LEN], Vec::new())).unwrap();
+        assert_eq!(read_until_packets(&mut receiver).len(), 1);
+
+        sender.write_packet(Packet::new(vec![1, 2, 3], Vec::new())).unwrap();
+        let packets = read_until_packets(&mut receiver);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, vec![1, 2, 3]);
+        assert!(receiver.read_buffer.buffered_len() == 0);
+    }
+
+    #[test]
+    fn many_reads_in_a_row_stay_correct() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let writer = std::thread::spawn(move || {
+            for i in 0 .. 2000u32 {
+                let fds = if i.is_multiple_of(100) { vec![dev_null()] } else { Vec::new() };
+                sender.write_packet(Packet::new(i.to_le_bytes().repeat(i as usize % 7 + 1), fds)).unwrap();
+            }
+        });
+
+        let mut received = 0u32;
+        while received < 2000 {
+            for packet in receiver.read_packets().unwrap() {
+                assert_eq!(packet.data, received.to_le_bytes().repeat(received as usize % 7 + 1));
+                assert_eq!(packet.fds.len(), usize::from(received.is_multiple_of(100)));
+                received += 1;
+            }
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn peer_credentials_match_own_uid() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let uid = unsafe { libc::getuid() };
+        assert_eq!(receiver.peer_credentials().unwrap().uid, uid);
+
+        sender.write_packet(Packet::new(vec![1, 2, 3], Vec::new())).unwrap();
+        read_until_packets(&mut receiver);
+        assert_eq!(receiver.last_credentials().map(|credentials| credentials.uid), Some(uid));
+    }
+
+    /// Whether a child process would inherit the file descriptor.
+    fn is_inherited(fd: BorrowedFd<'_>) -> bool {
+        use std::os::fd::AsRawFd;
+
+        // Spawning only returns once the child has called exec, so the child's file descriptors are final.
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let inherited = Path::new(&format!("/proc/{}/fd/{}", child.id(), fd.as_raw_fd())).exists();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        inherited
+    }
+
+    #[test]
+    fn sockets_are_not_inherited_by_children() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let channel = StreamChannel::open(&path).unwrap();
+        let (accepted, _) = socket.accept().unwrap();
+        let (left, right) = StreamChannel::pair().unwrap();
+
+        for fd in [socket.as_fd(), channel.as_fd(), accepted.as_fd(), left.as_fd(), right.as_fd()] {
+            assert!(!is_inherited(fd), "File descriptor {fd:?} was inherited.");
+        }
+    }
+
+    #[test]
+    fn accept_reports_peer_credentials() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let _channel = StreamChannel::open(&path).unwrap();
+
+        let (_accepted, credentials) = socket.accept().unwrap();
+        assert_eq!(credentials.uid, unsafe { libc::getuid() });
+        assert_eq!(credentials.pid, std::process::id() as i32);
+    }
+
+    fn dev_null() -> OwnedFd {
+        std::fs::File::open("/dev/null").unwrap().into()
+    }
+
+    #[test]
+    fn write_multiple_packets_at_once() {
+        let (mut sender, mut receiver) = blocking_pair();
+        sender.write_packets(vec![
+            Packet::new(vec![1], vec![dev_null()]),
+            Packet::new(vec![2, 2], Vec::new()),
+            Packet::new(vec![3, 3, 3], vec![dev_null(), dev_null()]),
+        ]).unwrap();
+
+        let mut packets = Vec::new();
+        while packets.len() < 3 {
+            packets.extend(read_until_packets(&mut receiver));
+        }
+
+        let summary: Vec<(Vec<u8>, usize)> = packets.into_iter()
+            .map(|packet| (packet.data, packet.fds.len()))
+            .collect();
+        assert_eq!(summary, vec![(vec![1], 1), (vec![2, 2], 0), (vec![3, 3, 3], 2)]);
+    }
+
+    #[test]
+    fn hundred_small_packets_arrive_in_order() {
+        let (mut sender, mut receiver) = blocking_pair();
+        sender.write_packets((0 .. 100u8).map(|i| Packet::new(vec![i; 3], Vec::new())).collect()).unwrap();
+
+        // All packets went out with a single syscall, so a single read picks all of them up.
+        let packets = receiver.read_packets().unwrap();
+        assert_eq!(packets.len(), 100);
+        for (i, packet) in packets.into_iter().enumerate() {
+            assert_eq!(packet.data, vec![i as u8; 3]);
+        }
+    }
+
+    #[test]
+    fn socket_path_resolution() {
+        let path = |uio: Option<&str>, xdg: Option<&str>| resolve_socket_path(uio.map(Into::into), xdg.map(Into::into));
+        assert_eq!(path(Some("/a/socket"), Some("/run/user/1000")), PathBuf::from("/a/socket"));
+        assert_eq!(path(None, Some("/run/user/1000")), PathBuf::from("/run/user/1000/uio/socket"));
+        assert_eq!(path(Some(""), None), PathBuf::from(DEFAULT_UIO_SOCKET_PATH));
+    }
+
+    #[test]
+    fn queued_packets_arrive_after_flush() {
+        let (mut sender, mut receiver) = blocking_pair();
+        rustix::fs::fcntl_setfl(&sender, OFlags::NONBLOCK).unwrap();
+
+        // Keep writing until the socket buffer is full and packets start getting queued.
+        let mut num_written = 0;
+        while !sender.has_pending_writes() {
+            sender.write_packet(Packet::new(vec![0; 16 * 1024], Vec::new())).unwrap();
+            num_written += 1;
+        }
+
+        let mut num_received = 0;
+        while num_received < num_written {
+            num_received += receiver.read_packets().unwrap().len();
+            sender.flush().unwrap();
+        }
+        assert!(!sender.has_pending_writes());
+    }
+
+    #[test]
+    fn open_with_retry_waits_for_server() {
+        let path = unique_socket_path();
+
+        let client_path = path.clone();
+        let client = std::thread::spawn(move || {
+            StreamChannel::open_with_retry(&client_path, Duration::from_secs(10))
+        });
+
+        std::thread::sleep(Duration::from_millis(50));
+        let socket = StreamSocket::open(path).unwrap();
+
+        assert!(client.join().unwrap().is_ok());
+        drop(socket);
+    }
+
+    #[test]
+    fn open_with_retry_times_out() {
+        let err = StreamChannel::open_with_retry(&unique_socket_path(), Duration::from_millis(50)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn packet_iterator_drains_lazily() {
+        let (mut sender, mut receiver) = blocking_pair();
+        sender.write_packets(vec![
+            Packet::new(vec![1], vec![dev_null(), dev_null()]),
+            Packet::new(vec![2], vec![dev_null()]),
+        ]).unwrap();
+
+        let mut packets = receiver.read_packets_iter().unwrap();
+        let first = packets.next().unwrap();
+        assert_eq!((first.data, first.fds.len()), (vec![1], 2));
+
+        // The second packet must still be in the read buffer along with its file descriptor.
+        assert_eq!(receiver.read_buffer.buffered_len(), PACKET_HEADER_LEN + 1);
+        assert_eq!(receiver.read_buffer.buffered_fd_count(), 1);
+        let second = receiver.read_buffer.drain().next().unwrap();
+        assert_eq!((second.data, second.fds.len()), (vec![2], 1));
+    }
+
+    #[test]
+    fn read_survives_signal() {
+        extern "C" fn do_nothing(_signal: libc::c_int) {}
+
+        // Install a handler without SA_RESTART, so the kernel makes a blocking recvmsg fail with EINTR.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = do_nothing as extern "C" fn(libc::c_int) as usize;
+            assert_eq!(libc::sigaction(libc::SIGUSR1, &action, std::ptr::null_mut()), 0);
+        }
+
+        let (mut sender, mut receiver) = blocking_pair();
+        let reader = std::thread::spawn(move || read_until_packets(&mut receiver).len());
+
+        std::thread::sleep(Duration::from_millis(50));
+        unsafe { libc::pthread_kill(reader.as_pthread_t(), libc::SIGUSR1) };
+        std::thread::sleep(Duration::from_millis(50));
+
+        sender.write_packet(Packet::new(vec![1, 2, 3], Vec::new())).unwrap();
+        assert_eq!(reader.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn typed_round_trip() {
+        let (mut client, mut server) = blocking_pair();
+
+        client.send_request(Request { serial: 5, msg: RequestMsg::Hello(HelloMsg::current()) }, vec![dev_null()]).unwrap();
+        let requests = server.recv_requests().unwrap();
+        assert!(matches!(requests[..], [Ok(Message { msg: Request { serial: 5, msg: RequestMsg::Hello(_) }, ref fds })] if fds.len() == 1));
+
+        server.send_event(Event { serial: Some(5), msg: EventMsg::AnnounceAccepted }, Vec::new()).unwrap();
+        let events = client.recv_events().unwrap();
+        assert!(matches!(events[..], [Ok(Message { msg: Event { serial: Some(5), msg: EventMsg::AnnounceAccepted }, .. })]));
+    }
+
+    #[test]
+    fn undecodable_packet_does_not_drop_the_events_before_it() {
+        let (mut client, mut server) = blocking_pair();
+
+        server.send_event(Event { serial: Some(1), msg: EventMsg::AnnounceAccepted }, Vec::new()).unwrap();
+        server.write_packet(Packet::new(vec![1, 2, 3], Vec::new())).unwrap();
+        let events = client.recv_events().unwrap();
+        assert!(matches!(events[..], [Ok(Message { msg: Event { serial: Some(1), .. }, .. }), Err(_)]));
+    }
+
+    #[test]
+    fn reading_empty_channel_returns_no_packets() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let mut channel = StreamChannel::open(&path).unwrap();
+        let (_accepted, _) = socket.accept().unwrap();
+
+        assert!(channel.read_packets().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_all_packets_reads_until_the_socket_is_empty() {
+        let path = unique_socket_path();
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let mut sender = StreamChannel::open(&path).unwrap();
+        let (mut receiver, _) = socket.accept().unwrap();
+
+        sender.write_packet(Packet::new(vec![5; 3 * RECEIVE_BUFFER_SIZE], Vec::new())).unwrap();
+        assert!(!sender.has_pending_writes());
+
+        // A single read only gets part of the packet.
+        assert!(receiver.read_packets().unwrap().is_empty());
+        let packets = receiver.read_all_packets().unwrap();
+        assert_eq!(packets.iter().map(Packet::len).collect::<Vec<_>>(), [3 * RECEIVE_BUFFER_SIZE]);
+        assert!(receiver.read_all_packets().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reading_closed_channel_reports_reset() {
+        let (mut sender, mut receiver) = blocking_pair();
+        sender.write_packet(Packet::new(vec![1, 2, 3], Vec::new())).unwrap();
+        drop(sender);
+
+        assert_eq!(receiver.read_packets().unwrap().len(), 1);
+        let Err(err) = receiver.read_packets() else { panic!("Reading a closed channel succeeded.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn packet_with_many_fds_round_trip() {
+        let (mut sender, mut receiver) = blocking_pair();
+        let fds: Vec<OwnedFd> = (0 .. 40).map(|_| dev_null()).collect();
+        sender.write_packet(Packet::new(vec![1, 2, 3], fds)).unwrap();
+
+        let packets = read_until_packets(&mut receiver);
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].data, vec![1, 2, 3]);
+        assert_eq!(packets[0].fds.len(), 40);
+    }
+
+    #[test]
+    fn encoding_too_many_fds_fails() {
+        // The unit type takes no bytes, so only the header can be split over several syscalls.
+        let max = (PACKET_HEADER_LEN * MAX_FDS_PER_SYSCALL).min(MAX_FDS_PER_PACKET);
+        let fds: Vec<OwnedFd> = (0 .. max + 1).map(|_| dev_null()).collect();
+        let Err(err) = Packet::encode(&BincodeCodec, &(), fds) else { panic!("Encoding too many fds succeeded.") };
+        assert!(matches!(err, CodecError::TooManyFds { count, max: reported_max } if count == max + 1 && reported_max == max));
+
+        let fds: Vec<OwnedFd> = (0 .. MAX_FDS_PER_PACKET + 1).map(|_| dev_null()).collect();
+        let Err(err) = Packet::encode(&BincodeCodec, &vec![0u8; 1024], fds) else { panic!("Encoding too many fds succeeded.") };
+        assert!(matches!(err, CodecError::TooManyFds { count: 257, max: MAX_FDS_PER_PACKET }));
+    }
+
+    #[test]
+    fn writing_too_many_fds_fails() {
+        let (mut sender, _receiver) = blocking_pair();
+        let fds: Vec<OwnedFd> = (0 .. MAX_FDS_PER_PACKET + 1).map(|_| dev_null()).collect();
+        let err = sender.write_packet(Packet::new(vec![0; 1024], fds)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(!sender.has_pending_writes());
+    }
+
+    #[test]
+    fn fds_without_packet_are_limited() {
+        let (sender, mut receiver) = blocking_pair();
+        // Every header claims that its packet carries no fds, so none of the sent fds belong to a packet.
+        let num_sends = MAX_BUFFERED_FDS / MAX_FDS_PER_SYSCALL + 1;
+        for _ in 0 .. num_sends {
+            let fds = (0 .. MAX_FDS_PER_SYSCALL).map(|_| dev_null()).collect();
+            OutgoingBatch { data: vec![0], sent: 0, fds }.send(sender.fd.as_fd()).unwrap();
+        }
+
+        let mut result = Ok(());
+        for _ in 0 .. num_sends {
+            result = receiver.read_packets().map(drop);
+            assert!(receiver.read_buffer.buffered_fd_count() <= MAX_BUFFERED_FDS);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    /// Creates a file descriptor that can be told apart from others by the size of the file it refers to.
+    fn tagged_fd(tag: u64) -> OwnedFd {
+        let fd = rustix::fs::memfd_create("tagged", rustix::fs::MemfdFlags::CLOEXEC).unwrap();
+        rustix::fs::ftruncate(&fd, tag).unwrap();
+        fd
+    }
+
+    fn tags(fds: &[OwnedFd]) -> Vec<u64> {
+        fds.iter().map(|fd| rustix::fs::fstat(fd).unwrap().st_size as u64).collect()
+    }
+
+    /// The header of a raw packet with the given data. The data itself is not included.
+    fn header(data: &[u8], num_fds: u16) -> Vec<u8> {
+        let mut header = (data.len() as u32).to_le_bytes().to_vec();
+        header.extend_from_slice(&num_fds.to_le_bytes());
+        header.push(PacketKind::Raw as u8);
+        #[cfg(feature = "checksum")]
+        header.extend_from_slice(&crc32(data).to_le_bytes());
+        header
+    }
+
+    /// Sends the bytes in a single syscall, with the file descriptors attached to the first of them.
+    fn send_raw(channel: &StreamChannel, data: Vec<u8>, fds: Vec<OwnedFd>) {
+        let mut batch = OutgoingBatch { data, sent: 0, fds };
+        batch.send(channel.fd.as_fd()).unwrap();
+        assert!(batch.is_sent());
+    }
+
+    /// Keeps reading until `count` packets have been received, returning the data and fd tags of each.
+    fn receive_tagged(channel: &mut StreamChannel, count: usize) -> Vec<(Vec<u8>, Vec<u64>)> {
+        let mut received = Vec::new();
+        while received.len() < count {
+            received.extend(channel.read_packets().unwrap().into_iter().map(|packet| {
+                let tags = tags(&packet.fds);
+                (packet.data, tags)
+            }));
+        }
+        received
+    }
+
+    #[test]
+    fn fds_stay_with_their_packet_when_the_body_is_split() {
+        let (sender, mut receiver) = blocking_pair();
+        // All fds of the first packet arrive with the first half of its body.
+        let mut first_half = header(&[1, 1, 1, 1], 2);
+        first_half.extend_from_slice(&[1, 1]);
+        send_raw(&sender, first_half, vec![tagged_fd(1), tagged_fd(2)]);
+        let mut second_half = vec![1, 1];
+        second_half.extend(header(&[2], 1));
+        second_half.push(2);
+        send_raw(&sender, second_half, vec![tagged_fd(3)]);
+
+        assert_eq!(receive_tagged(&mut receiver, 2), vec![
+            (vec![1, 1, 1, 1], vec![1, 2]),
+            (vec![2], vec![3]),
+        ]);
+    }
+
+    #[test]
+    fn fds_arriving_after_their_packet_are_matched_in_order() {
+        let (sender, mut receiver) = blocking_pair();
+        // The first packet is complete before its fds arrive, together with those of the second packet.
+        let mut first = header(&[1, 1], 2);
+        first.extend_from_slice(&[1, 1]);
+        send_raw(&sender, first, Vec::new());
+        let mut second = header(&[2], 1);
+        second.push(2);
+        send_raw(&sender, second, vec![tagged_fd(1), tagged_fd(2), tagged_fd(3)]);
+
+        assert_eq!(receive_tagged(&mut receiver, 2), vec![
+            (vec![1, 1], vec![1, 2]),
+            (vec![2], vec![3]),
+        ]);
+    }
+
+    #[test]
+    fn fds_spread_over_single_byte_reads_are_matched_in_order() {
+        let (sender, mut receiver) = blocking_pair();
+        let mut bytes = header(&[1, 1, 1], 2);
+        bytes.extend_from_slice(&[1, 1, 1]);
+        bytes.extend(header(&[], 0));
+        bytes.extend(header(&[3], 2));
+        bytes.push(3);
+        // Every byte goes in its own syscall, and the fds trickle in one at a time with the first few bytes.
+        let mut fds: Vec<OwnedFd> = (1 ..= 4).map(tagged_fd).collect();
+        for (i, byte) in bytes.into_iter().enumerate() {
+            let fds = if i % 2 == 0 && !fds.is_empty() { vec![fds.remove(0)] } else { Vec::new() };
+            send_raw(&sender, vec![byte], fds);
+        }
+
+        assert_eq!(receive_tagged(&mut receiver, 3), vec![
+            (vec![1, 1, 1], vec![1, 2]),
+            (vec![], vec![]),
+            (vec![3], vec![3, 4]),
+        ]);
+    }
+
+    #[test]
+    fn header_claiming_too_many_fds_is_rejected() {
+        let (sender, mut receiver) = blocking_pair();
+        let mut header = u32::to_le_bytes(4).to_vec();
+        header.extend_from_slice(&u16::to_le_bytes(MAX_FDS_PER_PACKET as u16 + 1));
+        header.push(PacketKind::Raw as u8);
+        header.resize(PACKET_HEADER_LEN, 0);
+        rustix::io::write(&sender.fd, &header).unwrap();
+
+        let Err(err) = receiver.read_packets() else { panic!("Reading a packet with too many fds succeeded.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    fn flipped_byte_fails_the_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let (sender, mut receiver) = blocking_pair();
+        let mut framed = Vec::new();
+        append_framed(&mut framed, &Packet::new(vec![1, 2, 3, 4], Vec::new()));
+        *framed.last_mut().unwrap() ^= 0x10;
+        rustix::io::write(&sender.fd, &framed).unwrap();
+
+        let Err(err) = receiver.read_packets() else { panic!("Reading a corrupted packet succeeded.") };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let err = err.into_inner().unwrap().downcast::<CodecError>().unwrap();
+        assert!(matches!(*err, CodecError::ChecksumMismatch { .. }));
+    }
+
+    #[cfg(feature = "wire-dump")]
+    #[test]
+    fn wire_dump_describes_packet() {
+        let packet = Packet::new(vec![0x01, 0xab, 0xff], vec![dev_null()]);
+        assert_eq!(dump_packet("Sending", &packet), "Sending packet of 3 bytes with 1 fds: 01 ab ff");
+    }
+
+    #[test]
+    fn second_server_is_rejected() {
+        let path = unique_socket_path();
+        let _first = StreamSocket::open(path.clone()).unwrap();
+
+        let err = StreamSocket::open(path.clone()).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+        assert!(err.to_string().contains("already running"));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn abstract_address_round_trip() {
+        let name = unique_socket_path().into_os_string().into_encoded_bytes();
+        let socket = StreamSocket::open(Address::Abstract(name.clone())).unwrap();
+        let mut sender = StreamChannel::open(Address::Abstract(name.clone())).unwrap();
+        let (mut receiver, _) = socket.accept().unwrap();
+
+        sender.write_packet(Packet::new(vec![1, 2, 3], Vec::new())).unwrap();
+        let packets = read_until_packets(&mut receiver);
+        assert_eq!(packets[0].data, vec![1, 2, 3]);
+
+        let err = StreamSocket::open(Address::Abstract(name)).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn activated_socket_is_adopted() {
+        use std::os::fd::IntoRawFd;
+
+        let path = unique_socket_path();
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let fd = OwnedFd::from(listener).into_raw_fd();
+        let pid = Some(std::process::id().to_string().into());
+
+        let socket = StreamSocket::adopt_listen_fd(pid, Some("1".into()), fd).unwrap();
+        let _channel = StreamChannel::open(&path).unwrap();
+        assert!(socket.accept().is_ok());
+
+        // The socket file belongs to whoever created it.
+        drop(socket);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_activation_is_ignored() {
+        use std::os::fd::AsRawFd;
+
+        let (left, _right) = blocking_pair();
+        let pid = Some((std::process::id() + 1).to_string().into());
+        assert!(StreamSocket::adopt_listen_fd(pid.clone(), Some("1".into()), left.fd.as_raw_fd()).is_none());
+
+        // Not a listening socket.
+        let pid = Some(std::process::id().to_string().into());
+        assert!(StreamSocket::adopt_listen_fd(pid, Some("1".into()), left.fd.as_raw_fd()).is_none());
+    }
+
+    #[test]
+    fn builder_sets_the_socket_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = unique_socket_path();
+        let _socket = StreamSocketBuilder::new().mode(0o600).backlog(4).open(path.clone()).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(StreamChannel::open(&path).is_ok());
+    }
+
+    #[test]
+    fn mode_of_abstract_socket_is_rejected() {
+        let name = format!("libuio-test-mode-{}", std::process::id()).into_bytes();
+        let Err(err) = StreamSocketBuilder::new().mode(0o600).open(Address::Abstract(name)) else {
+            panic!("Setting the mode of an abstract socket succeeded.")
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let path = unique_socket_path();
+        // Unlike StreamSocket, the standard library leaves the socket file behind.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let socket = StreamSocket::open(path.clone()).unwrap();
+        let _channel = StreamChannel::open(&path).unwrap();
+        assert!(socket.accept().is_ok());
+    }
+}
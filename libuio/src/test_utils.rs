@@ -0,0 +1,11 @@
+//! Helpers shared by the unit tests of the library.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Returns a path for a socket that no other test is using.
+pub fn unique_socket_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("libuio-test-{}-{id}.socket", std::process::id()))
+}
@@ -1,46 +1,45 @@
 #![allow(dead_code)]
 
-use std::path::Path;
+use std::ops::ControlFlow;
 
-use libuio::message::AnnounceMsg;
-use rustix::event::{PollFd, PollFlags};
-use libuio::socket::{Packet, StreamChannel};
+use libuio::client::{Disconnect, EventLoop, RetryPolicy, UioClient};
 
 fn main() {
-    // Ensure that the path to our socket is available.
-    let path = Path::new(libuio::socket::DEFAULT_UIO_SOCKET_PATH);
+    let path = libuio::socket::default_socket_path();
+    let policy = RetryPolicy { max_attempts: Some(30), ..RetryPolicy::default() };
 
-    // Create the actual socket.
-    let mut channel = StreamChannel::open(path)
-        .expect("Failed to connect to the UIO server!");
-
-    println!("Connected to server!");
-
-    let packet = Packet::try_from_request(libuio::message::RequestMsg::Announce(AnnounceMsg {
-        name: "Experimental Client".to_owned()
-    }), Vec::new()).unwrap();
+    // If the server goes away, wait for it to come back and introduce ourselves again.
+    loop {
+        // Also waits while the server is too busy to take us, rather than knocking on its door right away.
+        let client = UioClient::connect_with_retry(&path, &policy)
+            .expect("Failed to connect to the UIO server!");
 
-    channel.write_packet(packet).expect("Failed to write packet!");
+        println!("Connected to server!");
 
-    loop {
-        let mut to_poll = [PollFd::new(&channel, PollFlags::IN)];
-        rustix::event::poll(&mut to_poll, -1).expect("Failed to poll");
-        let events = to_poll[0].revents();
-        println!("Received events: {:?}", events);
-    
-        if events.contains(PollFlags::IN) {
-            println!("Received message!");
-            for packet in channel.read_packets().expect("Failed to read message!") {
-                let (message, _fds) = packet.try_into_event().expect("Failed to parse packet as event!");
-                println!("Received event: {message:?}");
-            }
-        }
-        if events.contains(PollFlags::ERR) {
-            panic!("Channel broken!");
-        }
-        if events.contains(PollFlags::HUP) {
-            panic!("Channel closed!");
+        match run_session(client) {
+            Ok(()) => return,
+            Err(err) if err.kind() == std::io::ErrorKind::Unsupported => panic!("{err}"),
+            Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => panic!("{err}"),
+            Err(err) => println!("Lost the connection to the server, reconnecting: {err}"),
         }
     }
 }
 
+/// Introduces the client to the server and stays connected, answering pings, until the server closes the
+/// connection. Returns an error if the connection to the server was lost.
+fn run_session(client: UioClient) -> Result<(), std::io::Error> {
+    let mut event_loop = EventLoop::new(client);
+    event_loop.client().announce("Experimental Client")?;
+    println!("The server accepted us.");
+
+    let disconnect = event_loop.run(|_, event| {
+        println!("Received an event: {:?}", event.msg);
+        ControlFlow::Continue(())
+    })?;
+    match disconnect {
+        Disconnect::ServerClosed => Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset, "The server closed the connection.",
+        )),
+        Disconnect::Stopped => Ok(()),
+    }
+}
@@ -0,0 +1,70 @@
+//! Compares how many allocations `poll_timeout` and `poll_timeout_into` perform, and how long they take.
+//! Run with `cargo bench`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use rustix::net::{AddressFamily, SocketFlags, SocketType};
+
+// The server is a binary, so the module gets compiled into the benchmark directly.
+#[allow(dead_code)]
+#[path = "../src/epoll.rs"]
+mod epoll;
+
+use epoll::{Epoll, Message};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const POLLS: usize = 100_000;
+
+/// Returns the average amount of allocations and the average time per poll spent by `poll`.
+fn measure(epoll: &Epoll<u64>, mut poll: impl FnMut(&Epoll<u64>) -> usize) -> (f64, Duration) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0 .. POLLS {
+        // The socket stays readable, so every poll reports it.
+        assert_eq!(poll(epoll), 1);
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    (allocations as f64 / POLLS as f64, elapsed / POLLS as u32)
+}
+
+fn main() {
+    let (local, remote) = rustix::net::socketpair(
+        AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None
+    ).unwrap();
+    let epoll: Epoll<u64> = Epoll::new().unwrap();
+    epoll.add(&local, 0).unwrap();
+    rustix::io::write(&remote, b"data").unwrap();
+
+    let allocating = measure(&epoll, |epoll| epoll.poll_timeout(Some(Duration::ZERO)).unwrap().len());
+    let mut events: Vec<Message<u64>> = Vec::new();
+    // The first poll grows the buffer; every poll after that should reuse it.
+    epoll.poll_timeout_into(Some(Duration::ZERO), &mut events).unwrap();
+    let reusing = measure(&epoll, |epoll| {
+        epoll.poll_timeout_into(Some(Duration::ZERO), &mut events).unwrap();
+        events.len()
+    });
+
+    println!("poll_timeout:      {:.3} allocations, {:?} per poll", allocating.0, allocating.1);
+    println!("poll_timeout_into: {:.3} allocations, {:?} per poll", reusing.0, reusing.1);
+    assert_eq!(reusing.0, 0.0, "poll_timeout_into should not allocate once the buffer has grown.");
+}
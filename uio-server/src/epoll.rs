@@ -1,8 +1,11 @@
+use std::cell::RefCell;
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
-use std::os::fd::{OwnedFd, AsFd};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::sync::Arc;
+use std::time::Duration;
 
 use rustix::event::epoll::{EventData, EventFlags};
+use rustix::event::EventfdFlags;
 use rustix::fd::AsRawFd;
 
 /// Contains all the open communication channels from all clients.
@@ -23,17 +26,30 @@ use rustix::fd::AsRawFd;
 /// starts reporting bogus messages or fails to report safe errors. That's why most crates just blanket mark
 /// epoll related functionality as "safe".
 /// 
-/// # Panics
-/// Panics if K::try_from(u64::from(key)) returns an error. It must always be possible do a round-trip
-/// conversion K -> u64 -> K.
+/// It must always be possible to do a round-trip conversion K -> u64 -> K. Events whose key cannot be converted
+/// back are ignored with a warning.
 pub struct Epoll<K> {
     epoll_fd: OwnedFd,
+    /// Where the kernel writes the events of a single wait. Kept around so polling does not allocate. Behind a
+    /// RefCell, because registrations borrow the epoll while it gets polled.
+    event_buffer: RefCell<Vec<libc::epoll_event>>,
     _key: PhantomData<K>,
 }
 
+/// How many events a single wait can report unless configured otherwise.
+const DEFAULT_EVENT_CAPACITY: usize = 8;
+/// The event buffer never grows beyond this many events. Anything beyond is left for the next poll.
+const MAX_EVENT_CAPACITY: usize = 1024;
+
+/// What happened to a registered file. If a file is both readable and writable, `Ready` is always returned
+/// before `Writable`, so replies written while handling `Ready` can be flushed by the subsequent `Writable`.
+/// `Broken` and `Hup` are only returned if the file is neither readable nor writable, because any remaining
+/// data should be read first; the breakage will then be noticed when reading.
 pub enum Message<K> {
     // Represents a EPOLLIN message.
     Ready(K),
+    // Represents a EPOLLOUT message.
+    Writable(K),
 
     // Represents a EPOLLERR message.
     Broken(K),
@@ -41,14 +57,63 @@ pub enum Message<K> {
     Hup(K),
 }
 
+/// Decides when the epoll reports that a file is ready.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// The file gets reported by every poll for as long as it is ready.
+    Level,
+    /// The file only gets reported when it becomes ready. Callers must read until the file would block, for
+    /// example with `StreamChannel::read_all_packets`, because leftover data will not cause another wakeup.
+    Edge,
+}
+
+impl TriggerMode {
+    fn flags(self) -> EventFlags {
+        match self {
+            TriggerMode::Level => EventFlags::empty(),
+            TriggerMode::Edge => EventFlags::ET,
+        }
+    }
+}
+
 impl<K> Epoll<K> {
     pub fn new() -> std::io::Result<Self> {
+        Self::with_capacity(DEFAULT_EVENT_CAPACITY)
+    }
+
+    /// Creates an epoll that can report `capacity` events per wait. If more files are ready than that, the
+    /// capacity grows, up to a limit.
+    pub fn with_capacity(capacity: usize) -> std::io::Result<Self> {
         Ok(Self {
             epoll_fd: rustix::event::epoll::create(rustix::event::epoll::CreateFlags::CLOEXEC)?,
+            event_buffer: RefCell::new(vec![libc::epoll_event { events: 0, u64: 0 }; capacity.clamp(1, MAX_EVENT_CAPACITY)]),
             _key: PhantomData,
         })
     }
 
+    /// Waits at most `timeout` milliseconds for events and writes them to the start of the buffer. Returns how
+    /// many events were written.
+    fn wait_raw(&self, buffer: &mut [libc::epoll_event], timeout: i32) -> std::io::Result<usize> {
+        // For some reason, rustix decided to make their epoll event structure packed.
+        // Which means I can't read its flags field in safe Rust.
+        // So I am going to just do the polling with libc instead.
+        let num_events = unsafe { libc::epoll_wait(
+            self.epoll_fd.as_raw_fd(),
+            buffer.as_mut_ptr(),
+            buffer.len() as i32,
+            timeout
+        ) };
+        if num_events < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                // A signal handler ran. Whatever it wants us to know will be reported by the next poll.
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(num_events as usize)
+    }
+
     pub fn delete(&self, file: impl AsFd) -> std::io::Result<()> {
         rustix::event::epoll::delete(
             &self.epoll_fd,
@@ -59,57 +124,375 @@ impl<K> Epoll<K> {
 
 impl<K: Into<u64>> Epoll<K> {
     pub fn add(&self, file: impl AsFd, key: K) -> std::io::Result<()> {
+        self.add_with_mode(file, key, TriggerMode::Level)
+    }
+
+    pub fn add_with_mode(&self, file: impl AsFd, key: K, mode: TriggerMode) -> std::io::Result<()> {
         rustix::event::epoll::add(
             &self.epoll_fd,
             file.as_fd(),
             EventData::new_u64(key.into()),
-            EventFlags::IN | EventFlags::ERR | EventFlags::HUP
+            EventFlags::IN | EventFlags::ERR | EventFlags::HUP | mode.flags()
         ).map_err(std::io::Error::from)
     }
+
+    /// Like `add`, but returns a guard that removes the file from the epoll when it is dropped.
+    ///
+    /// The guard borrows both the epoll and the file, so it cannot be stored next to them in the same struct.
+    /// Long-lived registrations such as those of clients therefore still use `add` and `delete`.
+    pub fn register<'a>(&'a self, file: BorrowedFd<'a>, key: K) -> std::io::Result<Registration<'a, K>> {
+        self.register_with_mode(file, key, TriggerMode::Level)
+    }
+
+    /// Like `register`, with a choice of trigger mode as for `add_with_mode`.
+    pub fn register_with_mode<'a>(&'a self, file: BorrowedFd<'a>, key: K, mode: TriggerMode)
+        -> std::io::Result<Registration<'a, K>>
+    {
+        self.add_with_mode(file, key, mode)?;
+        Ok(Registration { epoll: self, file })
+    }
+
+    /// Creates a waker that makes the epoll report `key` as ready, and thereby wakes up whoever is polling it.
+    /// The waker keeps getting reported until it is reset.
+    pub fn waker(&self, key: K) -> std::io::Result<Waker> {
+        let eventfd = rustix::event::eventfd(0, EventfdFlags::CLOEXEC | EventfdFlags::NONBLOCK)?;
+        self.add(&eventfd, key)?;
+        Ok(Waker { eventfd: Arc::new(eventfd) })
+    }
+
+    /// Changes whether the epoll should report that an already registered file is writable. Fails with a
+    /// `NotRegistered` error of kind `NotFound` if the file was never added or has been deleted.
+    pub fn modify(&self, file: impl AsFd, key: K, writable: bool) -> std::io::Result<()> {
+        self.modify_with_mode(file, key, writable, TriggerMode::Level)
+    }
+
+    /// Like `modify`, for files that were registered with `add_with_mode`. The mode must be passed again,
+    /// because the kernel replaces all flags of the registration.
+    pub fn modify_with_mode(&self, file: impl AsFd, key: K, writable: bool, mode: TriggerMode) -> std::io::Result<()> {
+        let mut flags = EventFlags::IN | EventFlags::ERR | EventFlags::HUP | mode.flags();
+        if writable {
+            flags |= EventFlags::OUT;
+        }
+        match rustix::event::epoll::modify(&self.epoll_fd, file.as_fd(), EventData::new_u64(key.into()), flags) {
+            Ok(()) => Ok(()),
+            Err(rustix::io::Errno::NOENT) => Err(std::io::Error::new(std::io::ErrorKind::NotFound, NotRegistered)),
+            Err(err) => Err(err.into()),
+        }
+    }
 }
 
-impl<K: TryFrom<u64>> Epoll<K> {
+/// Returned inside an `std::io::Error` when trying to modify a file that is not registered with the epoll.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NotRegistered;
+
+impl std::fmt::Display for NotRegistered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The file is not registered with the epoll.")
+    }
+}
+
+impl std::error::Error for NotRegistered {}
+
+/// Keeps a file registered with an epoll for as long as it lives. Returned by `Epoll::register`.
+pub struct Registration<'a, K> {
+    epoll: &'a Epoll<K>,
+    file: BorrowedFd<'a>,
+}
+
+impl<K> Drop for Registration<'_, K> {
+    fn drop(&mut self) {
+        if let Err(err) = self.epoll.delete(self.file) {
+            log::warn!("Failed to remove a file from the epoll: {err}");
+        }
+    }
+}
+
+/// Wakes up the thread that polls an epoll from any other thread. Returned by `Epoll::waker`; clones wake up
+/// the same epoll.
+#[derive(Clone)]
+pub struct Waker {
+    eventfd: Arc<OwnedFd>,
+}
+
+impl Waker {
+    pub fn wake(&self) -> std::io::Result<()> {
+        match rustix::io::write(&*self.eventfd, &1u64.to_ne_bytes()) {
+            // The counter is full, so the epoll is going to wake up anyway.
+            Ok(_) | Err(rustix::io::Errno::AGAIN) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Makes the epoll stop reporting this waker until the next call to `wake`. Wakeups from several threads
+    /// that arrived in the meantime are all reset at once.
+    pub fn reset(&self) -> std::io::Result<()> {
+        match rustix::io::read(&*self.eventfd, &mut [0; 8]) {
+            Ok(_) | Err(rustix::io::Errno::AGAIN) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl<K: TryFrom<u64> + Copy> Epoll<K> {
     pub fn poll(&self) -> std::io::Result<Vec<Message<K>>> {
-        // For some reason, rustix decided to make their epoll event structure packed.
-        // Which means I can't read its flags field in safe Rust.
-        // So I am going to just do the polling with libc instead.
-        let mut event_list = rustix::event::epoll::EventVec::with_capacity(8);
-        rustix::event::epoll::wait(&self.epoll_fd, &mut event_list, -1)?;
+        self.poll_timeout(None)
+    }
 
-        let mut event_list: [MaybeUninit<libc::epoll_event>; 8] = [MaybeUninit::uninit(); 8];
-        let num_events = unsafe { libc::epoll_wait(
-            self.epoll_fd.as_raw_fd(),
-            &mut event_list as *mut _ as *mut libc::epoll_event,
-            event_list.len() as i32,
-            -1
-        ) };
-        if num_events < 0 {
-            return Err(std::io::Error::last_os_error());
+    /// Like `poll`, but gives up after the timeout has passed, in which case no events are returned.
+    /// Waits indefinitely if the timeout is `None`.
+    pub fn poll_timeout(&self, timeout: Option<Duration>) -> std::io::Result<Vec<Message<K>>> {
+        let mut events = Vec::new();
+        self.poll_timeout_into(timeout, &mut events)?;
+        Ok(events)
+    }
+
+    /// Like `poll`, but replaces the contents of `out` with the events instead of allocating a new Vec.
+    /// Polling does not allocate once `out` has grown large enough.
+    pub fn poll_into(&self, out: &mut Vec<Message<K>>) -> std::io::Result<()> {
+        self.poll_timeout_into(None, out)
+    }
+
+    /// Like `poll_timeout`, but replaces the contents of `out` with the events instead of allocating a new Vec.
+    pub fn poll_timeout_into(&self, timeout: Option<Duration>, out: &mut Vec<Message<K>>) -> std::io::Result<()> {
+        let timeout = match timeout {
+            // Round up, so we do not wake up just before the timeout and then have to poll again.
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        self.wait_into(timeout, out)
+    }
+
+    /// Waits at most `timeout` milliseconds for events, or indefinitely if `timeout` is -1.
+    #[cfg(test)]
+    fn wait(&self, timeout: i32) -> std::io::Result<Vec<Message<K>>> {
+        let mut events = Vec::new();
+        self.wait_into(timeout, &mut events)?;
+        Ok(events)
+    }
+
+    fn wait_into(&self, timeout: i32, out: &mut Vec<Message<K>>) -> std::io::Result<()> {
+        out.clear();
+        let mut event_buffer = self.event_buffer.borrow_mut();
+        let mut num_events = self.wait_raw(&mut event_buffer, timeout)?;
+
+        // If the buffer filled up, more files may be ready. Grow the buffer and collect the rest right away, so
+        // that files do not have to wait for the next poll just because they come later in the kernel's list.
+        while num_events == event_buffer.len() && event_buffer.len() < MAX_EVENT_CAPACITY {
+            let start = event_buffer.len();
+            event_buffer.resize((start * 2).min(MAX_EVENT_CAPACITY), libc::epoll_event { events: 0, u64: 0 });
+            let num_new_events = self.wait_raw(&mut event_buffer[start ..], 0)?;
+
+            // Level-triggered files that were already reported go back to the end of the kernel's list, so
+            // seeing one of them again means that we went through the whole list. Their flags get merged.
+            let mut wrapped = false;
+            num_events = start;
+            for i in start .. start + num_new_events {
+                let event = event_buffer[i];
+                match event_buffer[.. start].iter_mut().find(|earlier| earlier.u64 == event.u64) {
+                    Some(earlier) => {
+                        earlier.events |= event.events;
+                        wrapped = true;
+                    },
+                    None => {
+                        event_buffer[num_events] = event;
+                        num_events += 1;
+                    },
+                }
+            }
+            if wrapped {
+                break;
+            }
         }
 
-        let mut result = Vec::new();
-        for i in 0 .. (num_events as usize) {
-            let event = unsafe { event_list[i].assume_init() };
+        for event in &event_buffer[0 .. num_events] {
             let flags = event.events as i32;
-            let key = match event.u64.try_into() {
+            let raw_key = event.u64;
+            let key = match raw_key.try_into() {
                 Ok(key) => key,
-                Err(_) => panic!("Failed to convert an u64 back to a poll key."),
+                Err(_) => {
+                    log::warn!("Ignoring an event with the invalid key {raw_key:#x}.");
+                    continue;
+                },
             };
 
-            if flags & libc::EPOLLIN != 0 {
-                result.push(Message::Ready(key));
+            let readable = flags & libc::EPOLLIN != 0;
+            let writable = flags & libc::EPOLLOUT != 0;
+            if readable {
+                out.push(Message::Ready(key));
+            }
+            if writable {
+                out.push(Message::Writable(key));
+            }
+            if readable || writable {
                 continue;
             }
             if flags & libc::EPOLLERR != 0 {
-                result.push(Message::Broken(key));
+                out.push(Message::Broken(key));
                 continue;
             }
             if flags & libc::EPOLLHUP != 0 {
-                result.push(Message::Hup(key));
+                out.push(Message::Hup(key));
                 continue;
             }
         }
 
-        Ok(result)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::net::{AddressFamily, SocketFlags, SocketType};
+
+    use super::*;
+
+    /// Registers one end of a socket pair, writes to the other end a few times, then returns how many of the
+    /// two subsequent polls reported the registered end to be readable.
+    fn count_wakeups(mode: TriggerMode) -> usize {
+        let (reader, writer) = rustix::net::socketpair(
+            AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None
+        ).unwrap();
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        epoll.add_with_mode(&reader, 0, mode).unwrap();
+
+        for _ in 0 .. 3 {
+            rustix::io::write(&writer, b"burst").unwrap();
+        }
+
+        (0 .. 2).filter(|_| {
+            let events = epoll.wait(0).unwrap();
+            matches!(events[..], [Message::Ready(0)])
+        }).count()
+    }
+
+    #[test]
+    fn edge_triggered_wakes_up_once() {
+        assert_eq!(count_wakeups(TriggerMode::Edge), 1);
+    }
+
+    #[test]
+    fn level_triggered_wakes_up_repeatedly() {
+        assert_eq!(count_wakeups(TriggerMode::Level), 2);
+    }
+
+    #[test]
+    fn waker_unblocks_poll_from_another_thread() {
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        let waker = epoll.waker(7).unwrap();
+        let thread = std::thread::spawn({
+            let waker = waker.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(20));
+                waker.wake().unwrap();
+            }
+        });
+
+        let events = epoll.poll().unwrap();
+        assert!(matches!(events[..], [Message::Ready(7)]));
+        thread.join().unwrap();
+
+        waker.reset().unwrap();
+        assert!(epoll.poll_timeout(Some(Duration::ZERO)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn poll_timeout_returns_without_events() {
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        let start = std::time::Instant::now();
+
+        let events = epoll.poll_timeout(Some(Duration::from_millis(50))).unwrap();
+        assert!(events.is_empty());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn writable_is_reported_after_ready() {
+        let (local, remote) = rustix::net::socketpair(
+            AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None
+        ).unwrap();
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        epoll.add(&local, 0).unwrap();
+        assert!(epoll.wait(0).unwrap().is_empty());
+        epoll.modify(&local, 0, true).unwrap();
+
+        let events = epoll.wait(0).unwrap();
+        assert!(matches!(events[..], [Message::Writable(0)]));
+
+        rustix::io::write(&remote, b"data").unwrap();
+        let events = epoll.wait(0).unwrap();
+        assert!(matches!(events[..], [Message::Ready(0), Message::Writable(0)]));
+    }
+
+    #[test]
+    fn modifying_an_unregistered_file_fails() {
+        let (local, _remote) = rustix::net::socketpair(
+            AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC, None
+        ).unwrap();
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+
+        let err = epoll.modify(&local, 0, true).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.get_ref().and_then(|err| err.downcast_ref()), Some(&NotRegistered));
+
+        epoll.add(&local, 0).unwrap();
+        epoll.delete(&local).unwrap();
+        assert!(epoll.modify(&local, 0, true).is_err());
+    }
+
+    #[test]
+    fn dropped_registration_stops_events() {
+        let (local, remote) = rustix::net::socketpair(
+            AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None
+        ).unwrap();
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        rustix::io::write(&remote, b"data").unwrap();
+
+        let registration = epoll.register(local.as_fd(), 0).unwrap();
+        assert!(matches!(epoll.wait(0).unwrap()[..], [Message::Ready(0)]));
+
+        drop(registration);
+        assert!(epoll.wait(0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn more_ready_files_than_the_capacity_are_reported_at_once() {
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        let pairs: Vec<_> = (0 .. 20u64).map(|key| {
+            let (local, remote) = rustix::net::socketpair(
+                AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None
+            ).unwrap();
+            epoll.add(&local, key).unwrap();
+            rustix::io::write(&remote, b"data").unwrap();
+            (local, remote)
+        }).collect();
+
+        for _ in 0 .. 2 {
+            let mut keys: Vec<u64> = epoll.wait(0).unwrap().into_iter().map(|message| match message {
+                Message::Ready(key) => key,
+                _ => panic!("Expected only ready files."),
+            }).collect();
+            keys.sort();
+            assert_eq!(keys, (0 .. 20).collect::<Vec<_>>());
+        }
+        drop(pairs);
+    }
+
+    #[test]
+    fn poll_into_reuses_the_buffer() {
+        let (local, remote) = rustix::net::socketpair(
+            AddressFamily::UNIX, SocketType::STREAM, SocketFlags::CLOEXEC | SocketFlags::NONBLOCK, None
+        ).unwrap();
+        let epoll: Epoll<u64> = Epoll::new().unwrap();
+        epoll.add(&local, 0).unwrap();
+        rustix::io::write(&remote, b"data").unwrap();
+
+        let mut events = Vec::with_capacity(4);
+        let buffer = events.as_ptr();
+        for _ in 0 .. 3 {
+            epoll.poll_timeout_into(Some(Duration::ZERO), &mut events).unwrap();
+            assert!(matches!(events[..], [Message::Ready(0)]));
+            assert_eq!(events.as_ptr(), buffer);
+        }
     }
 }
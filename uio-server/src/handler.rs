@@ -1,28 +1,520 @@
-use libuio::message::AnnounceMsg;
+use std::os::fd::OwnedFd;
 
-use crate::state::Client;
+use libuio::channel::Channel;
+use libuio::message::{
+    AnnounceMsg, Event, EventMsg, HelloMsg, Request, RequestMsg, ERROR_INVALID_BUFFER, ERROR_UNAUTHENTICATED,
+    ERROR_UNKNOWN_REQUEST, ERROR_UNPARSEABLE, PROTOCOL_VERSION,
+};
+use libuio::shm::{PixelBuffer, SharedBuffer};
+use log::{debug, info, warn};
 
-enum ClientState {
-    /// The client has not identified itself.
-    Unknown,
+use crate::state::{Client, ClientId, ClientName, ClientState, Sessions};
+
+/// The longest name, in bytes, that clients may announce themselves with unless configured otherwise.
+pub const DEFAULT_MAX_NAME_LEN: usize = 256;
+
+/// Tells the caller of `handle_ready_client` what should happen to the client afterwards.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Keep,
+    /// The client must be removed from the server. Any remaining requests have not been processed.
+    Disconnect,
+}
+
+/// Decides how the server responds to requests. The server takes care of the hello handshake, goodbyes,
+/// keeping track of which clients have announced themselves and listing them; every other request is passed
+/// to the handler.
+pub trait RequestHandler {
+    /// Returns the events that should be sent to the client in reply to the request.
+    fn on_request(&mut self, client_id: ClientId, request: RequestMsg, fds: Vec<OwnedFd>) -> Vec<EventMsg>;
+
+    /// Called when a client resumes the session of a client that disconnected earlier. Whatever the handler
+    /// keeps for `old_id`, such as shared buffers, belongs to `new_id` from now on.
+    fn on_resume(&mut self, _old_id: ClientId, _new_id: ClientId) {}
+
+    /// Called when a client is gone for good: it disconnected without a session to come back to, or the grace
+    /// period of its session is over. The handler can forget everything it keeps for the client.
+    fn on_disconnect(&mut self, _client_id: ClientId) {}
+}
+
+/// Accepts every announcement and otherwise does nothing.
+pub struct EchoHandler;
+
+impl RequestHandler for EchoHandler {
+    fn on_request(&mut self, _client_id: ClientId, request: RequestMsg, _fds: Vec<OwnedFd>) -> Vec<EventMsg> {
+        match request {
+            RequestMsg::Announce(AnnounceMsg { name }) => {
+                info!("The client {name} connected.");
+                vec![EventMsg::AnnounceAccepted]
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Reads and handles all requests that the client has sent, or defers them if the client exceeds its rate
+/// limit. Clients announcing a name longer than `max_name_len` bytes get rejected. Clients that resume a
+/// session take it out of `sessions`.
+pub fn handle_ready_client<C: Channel>(
+    client_id: ClientId, client: &mut Client<C>, handler: &mut dyn RequestHandler, max_name_len: usize,
+    sessions: &mut Sessions,
+) -> Verdict {
+    let packets = match client.read_packets() {
+        Ok(packets) => packets,
+        Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => {
+            info!("Client closed the channel.");
+            return Verdict::Disconnect;
+        },
+        Err(err) => {
+            warn!("Failed to read requests from client: {err}");
+            return Verdict::Disconnect;
+        },
+    };
+    if !packets.is_empty() {
+        client.mark_active();
+    }
+    client.defer_packets(packets);
+
+    handle_deferred_requests(client_id, client, handler, max_name_len, sessions)
+}
+
+/// Handles the requests that the client has sent, for as long as its rate limit allows. The rest stays deferred
+/// until this is called again after the rate limit has been refilled.
+pub fn handle_deferred_requests<C: Channel>(
+    client_id: ClientId, client: &mut Client<C>, handler: &mut dyn RequestHandler, max_name_len: usize,
+    sessions: &mut Sessions,
+) -> Verdict {
+    while let Some(packet) = client.next_allowed_packet() {
+        let (Request { serial, msg }, fds) = match packet.try_into() {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("Failed to parse a request from client: {err}");
+                send_error(client, None, ERROR_UNPARSEABLE, format!("Failed to parse the request: {err}"));
+                return Verdict::Disconnect;
+            },
+        };
+        debug!("Received request: {msg:?}");
+
+        match (client.state(), msg) {
+            (_, RequestMsg::Goodbye { reason }) => {
+                // The client is leaving for good, so there is no point in keeping its session around.
+                client.set_session_token(None);
+                match reason {
+                    Some(reason) => info!("Client said goodbye: {reason}"),
+                    None => info!("Client said goodbye."),
+                }
+                return Verdict::Disconnect;
+            },
+            (ClientState::AwaitingHello, RequestMsg::Hello(hello)) => {
+                if hello.version != PROTOCOL_VERSION {
+                    warn!("Rejecting a client speaking protocol version {}.", hello.version);
+                    reply(client, serial, EventMsg::VersionMismatch { server_version: PROTOCOL_VERSION });
+                    return Verdict::Disconnect;
+                }
+                reply(client, serial, EventMsg::Hello(HelloMsg::current()));
+                client.set_state(ClientState::Unknown);
+            },
+            (ClientState::AwaitingHello, _) => {
+                warn!("Client sent a request before saying hello.");
+                send_error(client, Some(serial), ERROR_UNAUTHENTICATED, "Say hello first.".to_owned());
+                return Verdict::Disconnect;
+            },
+            (_, RequestMsg::Hello(_)) => {
+                warn!("Client said hello twice.");
+                send_error(client, Some(serial), ERROR_UNKNOWN_REQUEST, "Hello was already said.".to_owned());
+                return Verdict::Disconnect;
+            },
+            (_, RequestMsg::Pong { nonce }) => client.record_pong(nonce),
+            (_, RequestMsg::ListClients) => client.request_client_list(serial),
+            (_, RequestMsg::Subscribe { topics }) => client.subscribe(topics),
+            (_, RequestMsg::GetStats) => {
+                let stats = client.stats();
+                reply(client, serial, EventMsg::Stats { stats });
+            },
+            (ClientState::Unknown, RequestMsg::Resume { token }) => {
+                let Some(session) = sessions.resume(token) else {
+                    warn!("Client tried to resume an unknown or expired session.");
+                    let reason = "The session does not exist or has expired.".to_owned();
+                    reply(client, serial, EventMsg::AnnounceRejected { reason });
+                    return Verdict::Disconnect;
+                };
+                info!("The client {} resumed its session.", session.name);
+                handler.on_resume(session.client_id, client_id);
+                client.set_state(ClientState::Announced);
+                client.set_name(session.name);
+                client.subscribe(session.subscriptions);
+                client.set_session_token(Some(token));
+                reply(client, serial, EventMsg::AnnounceAccepted);
+            },
+            (_, RequestMsg::Resume { .. }) => {
+                warn!("Client tried to resume a session after announcing itself.");
+                let reason = "The client has already announced itself.".to_owned();
+                reply(client, serial, EventMsg::AnnounceRejected { reason });
+                return Verdict::Disconnect;
+            },
+            (_, msg) => {
+                let (msg, fds) = match check_attached_buffer(msg, fds) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        warn!("Client attached an invalid buffer: {err}");
+                        send_error(client, Some(serial), ERROR_INVALID_BUFFER, format!("Invalid buffer: {err}"));
+                        return Verdict::Disconnect;
+                    },
+                };
+                if let RequestMsg::Announce(AnnounceMsg { name }) = &msg {
+                    if client.state() == ClientState::Announced {
+                        warn!("Client announced itself twice.");
+                        let reason = "The client has already announced itself.".to_owned();
+                        reply(client, serial, EventMsg::AnnounceRejected { reason });
+                        return Verdict::Disconnect;
+                    }
+                    let name = match ClientName::new(name.clone(), max_name_len) {
+                        Ok(name) => name,
+                        Err(reason) => {
+                            warn!("Rejecting a client with an invalid name: {reason}");
+                            reply(client, serial, EventMsg::AnnounceRejected { reason });
+                            return Verdict::Disconnect;
+                        },
+                    };
+                    client.set_state(ClientState::Announced);
+                    client.set_name(name);
+                }
+                for event in handler.on_request(client_id, msg, fds) {
+                    if matches!(event, EventMsg::AnnounceAccepted) {
+                        let token = sessions.issue_token();
+                        client.set_session_token(Some(token));
+                        reply(client, serial, EventMsg::SessionToken { token });
+                    }
+                    reply(client, serial, event);
+                }
+            },
+        }
+    }
+
+    Verdict::Keep
+}
+
+/// Makes sure that the buffer attached to a `ShareBuffer` or `AttachBuffer` request matches its description and
+/// is sealed, so the handler can map it without further checks. Other requests are returned as they are.
+fn check_attached_buffer(msg: RequestMsg, fds: Vec<OwnedFd>) -> std::io::Result<(RequestMsg, Vec<OwnedFd>)> {
+    match msg {
+        RequestMsg::ShareBuffer { len } => Ok(SharedBuffer::receive(len, fds)?.into_request()),
+        RequestMsg::AttachBuffer { width, height, stride, format } => {
+            Ok(PixelBuffer::receive(width, height, stride, format, fds)?.into_request())
+        },
+        msg => Ok((msg, fds)),
+    }
+}
+
+/// Sends an event to the client in response to the request with the given serial.
+fn reply<C: Channel>(client: &mut Client<C>, serial: u32, msg: EventMsg) {
+    send(client, Some(serial), msg);
 }
 
-impl ClientState {
-    fn new() -> ClientState {
-        ClientState::Unknown
+/// Tells the client what it did wrong. The caller is expected to disconnect the client afterwards.
+fn send_error<C: Channel>(client: &mut Client<C>, serial: Option<u32>, code: u32, message: String) {
+    send(client, serial, EventMsg::Error { code, message });
+}
+
+fn send<C: Channel>(client: &mut Client<C>, serial: Option<u32>, msg: EventMsg) {
+    // Failures are not fatal here: a broken channel will be noticed and cleaned up by the next read.
+    if let Err(err) = client.send_event(Event { serial, msg }, Vec::new()) {
+        warn!("Failed to write an event to client: {err}");
     }
 }
 
-pub fn handle_ready_client(client: &mut Client) {
-    for packet in client.channel_mut().read_packets().expect("Failed to read message!") {
-        let (message, _fds) = packet.try_into_request().expect("Failed to parse packet as request!");
-        println!("Received request: {message:?}");
+#[cfg(test)]
+mod tests {
+    use libuio::message::{PixelFormat, SerialCounter};
+    use libuio::socket::{Packet, PacketKind};
+
+    use super::*;
+    use crate::test_utils::{connected_client, mock_client, receive_events, send_request};
+
+    const TEST_ID: ClientId = ClientId { index: 0, generation: 0 };
+
+    fn test_sessions() -> Sessions {
+        Sessions::new(std::time::Duration::from_secs(60))
+    }
+
+    #[test]
+    fn replies_carry_request_serial() {
+        let (mut channel, mut client) = connected_client();
+        let mut serials = SerialCounter::new();
+        let sent = [serials.next_serial(), serials.next_serial()];
+        send_request(&mut channel, sent[0], RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, sent[1], RequestMsg::Announce(AnnounceMsg { name: "Client".to_owned() }));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+
+        // The announcement is answered with both a session token and the acceptance.
+        let received: Vec<Option<u32>> = receive_events(&mut channel).into_iter().map(|event| event.serial).collect();
+        assert_eq!(received, vec![Some(sent[0]), Some(sent[1]), Some(sent[1])]);
+    }
+
+    #[test]
+    fn matching_version_is_accepted() {
+        let (mut channel, mut client) = connected_client();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+        assert_eq!(client.state(), ClientState::Unknown);
+
+        let events = receive_events(&mut channel);
+        assert!(matches!(events[..], [Event { msg: EventMsg::Hello(HelloMsg { version: PROTOCOL_VERSION, .. }), .. }]));
+    }
+
+    #[test]
+    fn mismatching_version_is_rejected() {
+        let (mut channel, mut client) = connected_client();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg { version: PROTOCOL_VERSION + 1, features: 0 }));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+
+        let events = receive_events(&mut channel);
+        assert!(matches!(events[..], [Event { msg: EventMsg::VersionMismatch { server_version: PROTOCOL_VERSION }, .. }]));
+    }
+
+    #[test]
+    fn garbage_is_answered_with_an_error() {
+        let (mut channel, mut client) = connected_client();
+        channel.write_packet(Packet { data: vec![0xff; 8], kind: PacketKind::Request, fds: Vec::new() }).unwrap();
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+
+        let events = receive_events(&mut channel);
+        assert!(matches!(events[..], [Event { serial: None, msg: EventMsg::Error { code: ERROR_UNPARSEABLE, .. } }]));
+    }
+
+    #[test]
+    fn packet_of_the_wrong_kind_is_answered_with_an_error() {
+        let (mut channel, mut client) = connected_client();
+        channel.send_event(Event { serial: None, msg: EventMsg::Shutdown }, Vec::new()).unwrap();
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+
+        let events = receive_events(&mut channel);
+        assert!(matches!(events[..], [Event { serial: None, msg: EventMsg::Error { code: ERROR_UNPARSEABLE, .. } }]));
+    }
+
+    #[test]
+    fn request_before_hello_is_answered_with_an_error() {
+        let mut client = mock_client();
+        client.channel_mut().push_request(4, RequestMsg::Announce(AnnounceMsg { name: "Rude".to_owned() }));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+
+        let events = client.channel_mut().take_events();
+        assert!(matches!(events[..], [Event { serial: Some(4), msg: EventMsg::Error { code: ERROR_UNAUTHENTICATED, .. } }]));
+    }
+
+    #[test]
+    fn second_hello_is_answered_with_an_error() {
+        let mut client = mock_client();
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request(1, RequestMsg::Hello(HelloMsg::current()));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+
+        let events = client.channel_mut().take_events();
+        assert!(matches!(events[..], [_, Event { serial: Some(1), msg: EventMsg::Error { code: ERROR_UNKNOWN_REQUEST, .. } }]));
+    }
+
+    #[test]
+    fn goodbye_ends_the_session_and_skips_later_requests() {
+        struct CountingHandler(usize);
+        impl RequestHandler for CountingHandler {
+            fn on_request(&mut self, _client_id: ClientId, _request: RequestMsg, _fds: Vec<OwnedFd>) -> Vec<EventMsg> {
+                self.0 += 1;
+                vec![EventMsg::AnnounceAccepted]
+            }
+        }
+
+        let mut client = mock_client();
+        let mut handler = CountingHandler(0);
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request(1, RequestMsg::Announce(AnnounceMsg { name: "Leaving".to_owned() }));
+        client.channel_mut().push_request(2, RequestMsg::Goodbye { reason: Some("Done".to_owned()) });
+        client.channel_mut().push_shared_buffer(3);
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut handler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+        assert_eq!(handler.0, 1);
+        assert_eq!(client.session_token(), None);
+        // The goodbye itself does not get a reply.
+        assert_eq!(client.channel_mut().take_events().len(), 3);
+    }
+
+    #[test]
+    fn spurious_wakeup_keeps_client() {
+        let (_channel, mut client) = connected_client();
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+        assert_eq!(client.state(), ClientState::AwaitingHello);
+    }
+
+    #[test]
+    fn announce_is_accepted() {
+        let mut client = mock_client();
+        client.channel_mut().push_request(1, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request(2, RequestMsg::Announce(AnnounceMsg { name: "Mock".to_owned() }));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+        assert_eq!(client.state(), ClientState::Announced);
+
+        let events = client.channel_mut().take_events();
+        assert!(matches!(events[..], [
+            Event { serial: Some(1), msg: EventMsg::Hello(_) },
+            Event { serial: Some(2), msg: EventMsg::SessionToken { .. } },
+            Event { serial: Some(2), msg: EventMsg::AnnounceAccepted },
+        ]));
+    }
+
+    /// Says hello and announces the given name, then returns the verdict and the reply to the announcement.
+    fn announce(name: String) -> (Verdict, Event) {
+        let mut client = mock_client();
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request(1, RequestMsg::Announce(AnnounceMsg { name }));
+        let verdict = handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions());
+        (verdict, client.channel_mut().take_events().pop().unwrap())
+    }
+
+    #[test]
+    fn overlong_name_is_rejected() {
+        let (verdict, reply) = announce("x".repeat(DEFAULT_MAX_NAME_LEN + 1));
+        assert_eq!(verdict, Verdict::Disconnect);
+        assert!(matches!(reply, Event { serial: Some(1), msg: EventMsg::AnnounceRejected { .. } }));
+
+        let (verdict, _) = announce("x".repeat(DEFAULT_MAX_NAME_LEN));
+        assert_eq!(verdict, Verdict::Keep);
+    }
+
+    #[test]
+    fn name_with_newline_is_rejected() {
+        let (verdict, reply) = announce("Innocent\nERROR Forged log line".to_owned());
+        assert_eq!(verdict, Verdict::Disconnect);
+        assert!(matches!(reply, Event { serial: Some(1), msg: EventMsg::AnnounceRejected { .. } }));
+    }
+
+    #[test]
+    fn name_with_escape_sequence_is_rejected() {
+        let (verdict, reply) = announce("\x1b[2J\x1b[HCleared".to_owned());
+        assert_eq!(verdict, Verdict::Disconnect);
+        assert!(matches!(reply, Event { serial: Some(1), msg: EventMsg::AnnounceRejected { .. } }));
+    }
+
+    #[test]
+    fn second_announce_is_rejected() {
+        let mut client = mock_client();
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request(1, RequestMsg::Announce(AnnounceMsg { name: "First".to_owned() }));
+        client.channel_mut().push_request(2, RequestMsg::Announce(AnnounceMsg { name: "Second".to_owned() }));
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+        assert_eq!(client.name(), Some("First"));
+
+        let events = client.channel_mut().take_events();
+        let [.., Event { serial: Some(2), msg: EventMsg::AnnounceRejected { reason } }] = &events[..] else {
+            panic!("The second announce was not rejected: {events:?}")
+        };
+        assert!(!reason.is_empty());
+    }
+
+    #[test]
+    fn handler_reads_the_pattern_of_an_attached_buffer() {
+        struct ReadingHandler(Vec<u8>);
+        impl RequestHandler for ReadingHandler {
+            fn on_request(&mut self, _client_id: ClientId, request: RequestMsg, fds: Vec<OwnedFd>) -> Vec<EventMsg> {
+                if let RequestMsg::AttachBuffer { width, height, stride, format } = request {
+                    let buffer = PixelBuffer::receive(width, height, stride, format, fds).unwrap();
+                    self.0 = buffer.map().unwrap().to_vec();
+                }
+                Vec::new()
+            }
+        }
+
+        let (mut channel, mut client) = connected_client();
+        let pattern: Vec<u8> = (0 .. 2 * 16).collect();
+        let buffer = PixelBuffer::new("pattern", 3, 2, 16, PixelFormat::Xrgb8888, &pattern).unwrap();
+        let (msg, fds) = buffer.into_request();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        channel.send_request(Request { serial: 1, msg }, fds).unwrap();
+
+        let mut handler = ReadingHandler(Vec::new());
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut handler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+        assert_eq!(handler.0, pattern);
+    }
 
-        match message {
-            libuio::message::RequestMsg::Announce(announcement) => {
-                let AnnounceMsg { name } = announcement;
-                println!("The client {name} connected.");
+    #[test]
+    fn unsealed_buffer_is_answered_with_an_error() {
+        let fd = rustix::fs::memfd_create("unsealed", rustix::fs::MemfdFlags::CLOEXEC).unwrap();
+        rustix::fs::ftruncate(&fd, 16).unwrap();
+        let mut client = mock_client();
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        let msg = RequestMsg::AttachBuffer { width: 4, height: 1, stride: 16, format: PixelFormat::Argb8888 };
+        let packet = Packet::try_from((Request { serial: 1, msg }, vec![fd])).unwrap();
+        client.channel_mut().incoming.push_back(packet);
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+        let events = client.channel_mut().take_events();
+        assert!(matches!(events[..], [_, Event { serial: Some(1), msg: EventMsg::Error { code: ERROR_INVALID_BUFFER, .. } }]));
+    }
+
+    #[test]
+    fn unsealed_shared_buffer_is_answered_with_an_error() {
+        let fd = rustix::fs::memfd_create("unsealed", rustix::fs::MemfdFlags::CLOEXEC).unwrap();
+        rustix::fs::ftruncate(&fd, 16).unwrap();
+        let mut client = mock_client();
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request_with_fds(1, RequestMsg::ShareBuffer { len: 16 }, vec![fd]);
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Disconnect);
+        let events = client.channel_mut().take_events();
+        assert!(matches!(events[..], [_, Event { serial: Some(1), msg: EventMsg::Error { code: ERROR_INVALID_BUFFER, .. } }]));
+    }
+
+    #[test]
+    fn custom_handler_sees_requests() {
+        struct CountingHandler(usize);
+        impl RequestHandler for CountingHandler {
+            fn on_request(&mut self, _client_id: ClientId, _request: RequestMsg, _fds: Vec<OwnedFd>) -> Vec<EventMsg> {
+                self.0 += 1;
+                Vec::new()
             }
         }
+
+        let mut client = mock_client();
+        let mut handler = CountingHandler(0);
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        for serial in 1 ..= 3 {
+            client.channel_mut().push_shared_buffer(serial);
+        }
+
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut handler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+        assert_eq!(handler.0, 3);
+        // Only the hello got a reply, because the handler did not reply to anything.
+        assert_eq!(client.channel_mut().take_events().len(), 1);
+    }
+
+    #[test]
+    fn stats_count_the_traffic_before_the_reply() {
+        let request_len = |msg| Packet::try_from((Request { serial: 0, msg }, Vec::new())).unwrap().len() as u64;
+        let mut client = mock_client();
+        client.channel_mut().push_request(0, RequestMsg::Hello(HelloMsg::current()));
+        client.channel_mut().push_request(1, RequestMsg::GetStats);
+        assert_eq!(handle_ready_client(TEST_ID, &mut client, &mut EchoHandler, DEFAULT_MAX_NAME_LEN, &mut test_sessions()), Verdict::Keep);
+
+        let events = client.channel_mut().take_events();
+        let [hello, Event { serial: Some(1), msg: EventMsg::Stats { stats } }] = &events[..] else {
+            panic!("Got {events:?}");
+        };
+        let hello_len = Packet::try_from((Event { serial: hello.serial, msg: EventMsg::Hello(HelloMsg::current()) }, Vec::new()))
+            .unwrap().len() as u64;
+        assert_eq!(stats.packets_read, 2);
+        assert_eq!(stats.bytes_read, request_len(RequestMsg::Hello(HelloMsg::current())) + request_len(RequestMsg::GetStats));
+        assert_eq!(stats.packets_written, 1);
+        assert_eq!(stats.bytes_written, hello_len);
+
+        // By now, the stats reply has been counted as well.
+        assert_eq!(client.packets_written(), 2);
+        assert!(client.bytes_written() > hello_len);
     }
 }
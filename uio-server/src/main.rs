@@ -4,92 +4,1338 @@ mod handler;
 mod state;
 mod epoll;
 mod poll;
+mod timer;
 
-use std::collections::HashMap;
-use std::path::Path;
+#[cfg(test)]
+mod test_utils;
 
-use anyhow::Context;
-use epoll::Epoll;
-use poll::PollId;
-use libuio::socket::StreamSocket;
-use rustix::fd::{AsFd, AsRawFd, RawFd};
-use state::Client;
+use std::io::Read;
+use std::mem::Discriminant;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use epoll::{Epoll, Waker};
+use handler::{handle_deferred_requests, handle_ready_client, EchoHandler, RequestHandler, Verdict, DEFAULT_MAX_NAME_LEN};
+use poll::{ListenerId, PollId};
+use libuio::channel::Channel;
+use libuio::message::{Event, EventMsg, ERROR_PERMISSION_DENIED};
+use libuio::socket::{Credentials, Packet, StreamChannel, StreamSocket};
+use log::{info, trace, warn};
+use state::{Client, ClientId, ClientState, Clients, RateLimit, Sessions};
+use timer::Timer;
+
+/// Clients that have not sent anything for this long get disconnected.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Clients that have not announced themselves this long after connecting get disconnected.
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the session of a client that disconnected without saying goodbye is kept for it to resume.
+const DEFAULT_SESSION_GRACE_PERIOD: Duration = Duration::from_secs(30);
+/// How often clients get pinged to check whether they are still responsive.
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+/// Clients that fail to answer this many pings in a row get disconnected.
+const DEFAULT_MAX_MISSED_PONGS: u32 = 3;
+/// Clients that let more than this many bytes pile up without reading them get disconnected.
+const DEFAULT_MAX_QUEUED_BYTES: usize = 4 * 1024 * 1024;
+/// New connections get turned away while this many clients are connected.
+const DEFAULT_MAX_CLIENTS: usize = 1024;
+/// How many packets clients may send, unless configured otherwise.
+const DEFAULT_RATE_LIMIT: RateLimit = RateLimit { packets_per_second: 1000, burst: 1000 };
+/// Clients that exceed their rate limit this many ticks in a row get disconnected.
+const DEFAULT_MAX_RATE_VIOLATIONS: u32 = 5;
+/// How many connections get accepted from a listener before the clients get their turn again.
+const DEFAULT_MAX_ACCEPT_BURST: usize = 64;
+/// How often the server does its periodic housekeeping, regardless of client activity.
+const DEFAULT_TICK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the server waits for queued events to be sent to the clients when shutting down.
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
 
 struct Program {
     epoll: Epoll<PollId>,
+    /// The events of the most recent poll. Reused between polls to avoid allocating.
+    events: Vec<epoll::Message<PollId>>,
+    /// The sockets that clients connect to, indexed by their ListenerId.
+    sockets: Vec<StreamSocket>,
+
+    /// All connected clients.
+    ///
+    /// The ids are part of the keys registered with the epoll, so events can be routed to their client without
+    /// hashing. Ids of removed clients never refer to later clients, so stale events are harmless.
+    clients: Clients,
+    max_clients: usize,
+    /// How many connections may be accepted per readiness of a listener. The rest wait for the next poll, so a
+    /// storm of connections cannot starve the clients that are already connected.
+    max_accept_burst: usize,
+
+    /// How long clients may stay silent before they get disconnected. `None` disables the timeout.
+    idle_timeout: Option<Duration>,
+    /// How long clients may take to announce themselves after connecting. `None` disables the deadline.
+    handshake_timeout: Option<Duration>,
+    /// Sessions of clients that disconnected recently, which they can resume by reconnecting.
+    sessions: Sessions,
+    /// Only clients running as this user may list the other clients. The user that the server runs as.
+    admin_uid: u32,
+
+    /// Decides how to respond to the requests of clients.
+    handler: Box<dyn RequestHandler>,
+    /// The longest name, in bytes, that clients may announce themselves with.
+    max_name_len: usize,
+
+    /// How often clients that have said hello get pinged. `None` disables the heartbeat.
+    heartbeat_interval: Option<Duration>,
+    max_missed_pongs: u32,
+    /// The nonce for the next ping.
+    next_nonce: u64,
+
+    /// Slow-consumer limit: how many bytes may be queued for a single client.
+    max_queued_bytes: usize,
+
+    /// Limits how fast the requests of each client get handled. `None` disables the limit.
+    rate_limit: Option<RateLimit>,
+    /// How many ticks in a row a client may exceed its rate limit before it gets disconnected. `None` lets
+    /// clients exceed it indefinitely; their requests just get handled later.
+    max_rate_violations: Option<u32>,
+
+    /// Whether clients only get the latest of the coalescable events that are broadcast while their channel is
+    /// backed up. Off by default, so that every client sees every event.
+    coalescing: bool,
+
+    /// Wakes up the main loop for `on_tick()`.
+    tick_timer: Timer,
+    /// How often `on_tick()` has been called.
+    ticks: u64,
+
+    /// Lets other threads wake up the main loop.
+    waker: Waker,
+
+    /// Becomes readable when SIGINT or SIGTERM arrives. Only present after `install_signal_handlers()`.
+    signal_pipe: Option<UnixStream>,
+    shutdown_requested: bool,
 }
 
-fn main() -> ! {
-    // Ensure that the path to our socket is available.
-    let path = Path::new(libuio::socket::DEFAULT_UIO_SOCKET_PATH);
-    if path.exists() {
-        std::fs::remove_file(path).expect("Failed to free the occupied socket path");
+impl Program {
+    fn new(socket: StreamSocket) -> Program {
+        Program::with_handler(socket, Box::new(EchoHandler))
+    }
+
+    fn with_handler(socket: StreamSocket, handler: Box<dyn RequestHandler>) -> Program {
+        let epoll: Epoll<PollId> = Epoll::new().expect("Failed to create an epoll instance.");
+        epoll.add(&socket, PollId::Socket(ListenerId(0))).expect("Failed to add socket to epoll.");
+        let tick_timer = Timer::new(Some(DEFAULT_TICK_INTERVAL)).expect("Failed to create a timer.");
+        epoll.add(&tick_timer, PollId::Timer).expect("Failed to add timer to epoll.");
+        let waker = epoll.waker(PollId::Wakeup).expect("Failed to create a waker.");
+
+        Program {
+            epoll,
+            events: Vec::new(),
+            sockets: vec![socket],
+            clients: Clients::new(),
+            max_clients: DEFAULT_MAX_CLIENTS,
+            max_accept_burst: DEFAULT_MAX_ACCEPT_BURST,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            handshake_timeout: Some(DEFAULT_HANDSHAKE_TIMEOUT),
+            sessions: Sessions::new(DEFAULT_SESSION_GRACE_PERIOD),
+            admin_uid: rustix::process::getuid().as_raw(),
+            handler,
+            max_name_len: DEFAULT_MAX_NAME_LEN,
+            heartbeat_interval: Some(DEFAULT_HEARTBEAT_INTERVAL),
+            max_missed_pongs: DEFAULT_MAX_MISSED_PONGS,
+            next_nonce: 0,
+            max_queued_bytes: DEFAULT_MAX_QUEUED_BYTES,
+            rate_limit: Some(DEFAULT_RATE_LIMIT),
+            max_rate_violations: Some(DEFAULT_MAX_RATE_VIOLATIONS),
+            coalescing: false,
+            tick_timer,
+            ticks: 0,
+            waker,
+            signal_pipe: None,
+            shutdown_requested: false,
+        }
+    }
+
+    /// Starts accepting clients on another socket, in addition to the one the program was created with.
+    fn add_listener(&mut self, socket: StreamSocket) -> std::io::Result<ListenerId> {
+        let id = ListenerId(self.sockets.len().try_into().map_err(|_| {
+            std::io::Error::other("Too many sockets to listen on.")
+        })?);
+        self.epoll.add(&socket, PollId::Socket(id))?;
+        self.sockets.push(socket);
+        Ok(id)
+    }
+
+    /// Makes SIGINT and SIGTERM shut the server down gracefully instead of killing it.
+    fn install_signal_handlers(&mut self) -> std::io::Result<()> {
+        let (read_end, write_end) = UnixStream::pair()?;
+        read_end.set_nonblocking(true)?;
+        write_end.set_nonblocking(true)?;
+        for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            signal_hook::low_level::pipe::register(signal, write_end.try_clone()?)?;
+        }
+
+        self.epoll.add(&read_end, PollId::Signal)?;
+        self.signal_pipe = Some(read_end);
+        Ok(())
+    }
+
+    /// Returns a handle with which other threads can make the main loop finish its current poll.
+    fn waker(&self) -> Waker {
+        self.waker.clone()
+    }
+
+    /// Handles events until a shutdown is requested, then shuts down.
+    fn run(&mut self) {
+        while !self.shutdown_requested {
+            self.step();
+        }
+        self.shutdown();
+    }
+
+    /// Tells every client that the server is going away and gives the clients a moment to receive that.
+    fn shutdown(&mut self) {
+        info!("Shutting down.");
+        for id in self.clients.ids() {
+            let Some(client) = self.clients.get_mut(id) else { continue };
+            if client.state() == ClientState::AwaitingHello {
+                continue;
+            }
+            if let Err(err) = client.send_event(Event { serial: None, msg: EventMsg::Shutdown }, Vec::new()) {
+                self.disconnect_client(id, format_args!("failed to announce the shutdown: {err}"));
+                continue;
+            }
+            self.update_write_interest(id);
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+        while self.clients.values().any(Client::wants_write) {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Not all clients have been told about the shutdown.");
+                // Otherwise dropping the program would wait for each of them in turn.
+                let stuck: Vec<ClientId> = self.clients.ids().into_iter()
+                    .filter(|&id| self.clients.get(id).is_some_and(Client::wants_write))
+                    .collect();
+                for id in stuck {
+                    self.disconnect_client(id, "it did not take the shutdown notice in time");
+                }
+                break;
+            }
+            let mut events = std::mem::take(&mut self.events);
+            self.epoll.poll_timeout_into(Some(deadline - now), &mut events)
+                .expect("Failed to poll from the epoll.");
+            for event in events.drain(..) {
+                if let epoll::Message::Writable(_) = event {
+                    self.handle_event(event);
+                }
+            }
+            self.events = events;
+        }
+    }
+
+    fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    fn set_handshake_timeout(&mut self, handshake_timeout: Option<Duration>) {
+        self.handshake_timeout = handshake_timeout;
+    }
+
+    fn set_session_grace_period(&mut self, grace_period: Duration) {
+        self.sessions.set_grace_period(grace_period);
+    }
+
+    /// Changes how often `on_tick()` gets called. `None` stops the tick.
+    fn set_tick_interval(&mut self, interval: Option<Duration>) {
+        self.tick_timer.set_interval(interval).expect("Failed to set the timer.");
+    }
+
+    fn set_heartbeat(&mut self, interval: Option<Duration>, max_missed_pongs: u32) {
+        self.heartbeat_interval = interval;
+        self.max_missed_pongs = max_missed_pongs;
+    }
+
+    fn set_max_name_len(&mut self, max_name_len: usize) {
+        self.max_name_len = max_name_len;
+    }
+
+    fn set_max_accept_burst(&mut self, max_accept_burst: usize) {
+        self.max_accept_burst = max_accept_burst;
+    }
+
+    fn set_max_clients(&mut self, max_clients: usize) {
+        self.max_clients = max_clients;
+    }
+
+    fn set_max_queued_bytes(&mut self, max_queued_bytes: usize) {
+        self.max_queued_bytes = max_queued_bytes;
     }
 
+    /// Applies to connected clients as well as future ones.
+    fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>, max_violations: Option<u32>) {
+        self.rate_limit = rate_limit;
+        self.max_rate_violations = max_violations;
+        for (_, client) in self.clients.iter_mut() {
+            client.set_rate_limit(rate_limit);
+        }
+    }
+
+    /// Applies to connected clients as well as future ones.
+    fn set_coalescing(&mut self, coalescing: bool) {
+        self.coalescing = coalescing;
+        for (_, client) in self.clients.iter_mut() {
+            client.set_coalescing(coalescing);
+        }
+    }
+
+    /// Waits until at least one event happens or a timer of some client expires, then handles everything that
+    /// happened.
+    fn step(&mut self) {
+        // Taken out of self for the duration of the loop, because handling an event needs all of self.
+        let mut events = std::mem::take(&mut self.events);
+        self.epoll.poll_timeout_into(self.time_until_next_deadline(), &mut events)
+            .expect("Failed to poll from the epoll.");
+        trace!("Received {} events.", events.len());
+
+        for event in events.drain(..) {
+            self.handle_event(event);
+        }
+        self.events = events;
+
+        self.remove_idle_clients();
+        self.remove_unannounced_clients();
+        self.send_heartbeats();
+    }
+
+    /// Periodic housekeeping that does not belong to any single client. Called every tick interval, no matter
+    /// whether the clients are doing anything.
+    fn on_tick(&mut self) {
+        self.ticks += 1;
+        for id in self.sessions.remove_expired() {
+            self.handler.on_disconnect(id);
+        }
+        self.refill_rate_limits();
+    }
+
+    /// Refills the rate limit of every client and handles the requests that were deferred because of it.
+    /// Clients that keep exceeding their rate limit get disconnected.
+    fn refill_rate_limits(&mut self) {
+        for id in self.clients.ids() {
+            let Some(client) = self.clients.get_mut(id) else { continue };
+            client.refill_rate_limit();
+            if self.max_rate_violations.is_some_and(|max| client.rate_violations() > max) {
+                self.disconnect_client(id, "kept exceeding its rate limit");
+                continue;
+            }
+            if !client.is_throttled() {
+                continue;
+            }
+            let verdict = handle_deferred_requests(
+                id, client, self.handler.as_mut(), self.max_name_len, &mut self.sessions,
+            );
+            self.finish_handling(id, verdict);
+        }
+    }
+
+    /// How long it takes until the first client would time out or needs to be pinged.
+    fn time_until_next_deadline(&self) -> Option<Duration> {
+        let idle_deadline = self.idle_timeout.and_then(|idle_timeout| {
+            let first_activity = self.clients.values().map(Client::last_activity).min()?;
+            Some(first_activity + idle_timeout)
+        });
+        let handshake_deadline = self.handshake_timeout.and_then(|handshake_timeout| {
+            let first_connection = self.clients.values()
+                .filter(|client| client.state() != ClientState::Announced)
+                .map(Client::connected_at)
+                .min()?;
+            Some(first_connection + handshake_timeout)
+        });
+        let heartbeat_deadline = self.heartbeat_interval.and_then(|interval| {
+            let first_ping = self.clients.values()
+                .filter(|client| client.state() != ClientState::AwaitingHello)
+                .map(Client::last_ping)
+                .min()?;
+            Some(first_ping + interval)
+        });
+
+        let deadline = [idle_deadline, handshake_deadline, heartbeat_deadline].into_iter().flatten().min()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    fn remove_idle_clients(&mut self) {
+        let Some(idle_timeout) = self.idle_timeout else { return };
+        let now = Instant::now();
+        let idle_clients: Vec<ClientId> = self.clients.iter()
+            .filter(|(_, client)| now.duration_since(client.last_activity()) >= idle_timeout)
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in idle_clients {
+            self.disconnect_client(id, "idle for too long");
+        }
+    }
+
+    /// Disconnects clients that have not announced themselves in time. They get no explanation.
+    fn remove_unannounced_clients(&mut self) {
+        let Some(handshake_timeout) = self.handshake_timeout else { return };
+        let now = Instant::now();
+        let late_clients: Vec<ClientId> = self.clients.iter()
+            .filter(|(_, client)| client.state() != ClientState::Announced)
+            .filter(|(_, client)| now.duration_since(client.connected_at()) >= handshake_timeout)
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in late_clients {
+            self.disconnect_client(id, "did not announce itself in time");
+        }
+    }
+
+    /// Pings every client whose ping is due, and disconnects clients that have missed too many pongs. Clients
+    /// that have not said hello yet do not know about pings and are left alone.
+    fn send_heartbeats(&mut self) {
+        let Some(interval) = self.heartbeat_interval else { return };
+        let now = Instant::now();
+        let due_clients: Vec<ClientId> = self.clients.iter()
+            .filter(|(_, client)| client.state() != ClientState::AwaitingHello)
+            .filter(|(_, client)| now.duration_since(client.last_ping()) >= interval)
+            .map(|(id, _)| id)
+            .collect();
+
+        for id in due_clients {
+            let nonce = self.next_nonce;
+            self.next_nonce = self.next_nonce.wrapping_add(1);
+
+            let Some(client) = self.clients.get_mut(id) else { continue };
+            client.record_ping(nonce);
+            if client.missed_pongs() >= self.max_missed_pongs {
+                self.disconnect_client(id, "missed too many pongs");
+                continue;
+            }
+
+            let ping = Event { serial: None, msg: EventMsg::Ping { nonce } };
+            if let Err(err) = client.send_event(ping, Vec::new()) {
+                self.disconnect_client(id, format_args!("failed to ping: {err}"));
+                continue;
+            }
+            self.update_write_interest(id);
+        }
+    }
+
+    fn handle_event(&mut self, event: epoll::Message<PollId>) {
+        match event {
+            epoll::Message::Ready(key) => match key {
+                PollId::Client(id) => {
+                    trace!("Client ready.");
+                    let Some(client) = self.clients.get_mut(id) else { return };
+                    let verdict = handle_ready_client(
+                        id, client, self.handler.as_mut(), self.max_name_len, &mut self.sessions,
+                    );
+                    self.finish_handling(id, verdict);
+                },
+                PollId::Socket(listener) => {
+                    trace!("Socket {listener:?} ready.");
+                    self.accept_clients(listener);
+                },
+                PollId::Signal => {
+                    // The content of the pipe does not matter, but it must be drained so the epoll stops
+                    // reporting it.
+                    if let Some(pipe) = &mut self.signal_pipe {
+                        while pipe.read(&mut [0; 64]).is_ok_and(|bytes| bytes > 0) {}
+                    }
+                    info!("Received a signal to shut down.");
+                    self.shutdown_requested = true;
+                },
+                PollId::Wakeup => {
+                    trace!("Woken up by another thread.");
+                    if let Err(err) = self.waker.reset() {
+                        warn!("Failed to reset the waker: {err}");
+                    }
+                },
+                PollId::Timer => {
+                    // Ticks that were missed because the loop was busy are not made up for.
+                    match self.tick_timer.take_expirations() {
+                        Ok(0) => (),
+                        Ok(_) => self.on_tick(),
+                        Err(err) => warn!("Failed to read the timer: {err}"),
+                    }
+                },
+            },
+            epoll::Message::Writable(key) => match key {
+                PollId::Client(id) => {
+                    let Some(client) = self.clients.get_mut(id) else { return };
+                    if let Err(err) = client.flush() {
+                        self.disconnect_client(id, format_args!("failed to write: {err}"));
+                        return;
+                    }
+                    self.update_write_interest(id);
+                },
+                PollId::Socket(_) | PollId::Signal | PollId::Timer | PollId::Wakeup => (),
+            },
+            epoll::Message::Broken(key) | epoll::Message::Hup(key) => match key {
+                PollId::Client(id) => {
+                    self.disconnect_client(id, "the channel broke");
+                },
+                PollId::Socket(listener) => panic!("Socket {listener:?} broken!"),
+                PollId::Signal => panic!("Signal pipe broken!"),
+                PollId::Timer => panic!("Timer broken!"),
+                PollId::Wakeup => panic!("Waker broken!"),
+            },
+        }
+    }
+
+    /// Follows up on handling a client's requests: disconnects the client if that is the verdict, or sends it
+    /// what its requests asked for.
+    fn finish_handling(&mut self, id: ClientId, verdict: Verdict) {
+        if verdict == Verdict::Disconnect {
+            self.disconnect_client(id, "the request handler asked for it");
+            return;
+        }
+        self.answer_client_list_requests(id);
+        self.update_write_interest(id);
+    }
+
+    /// Accepts the connections that are waiting on the listener, up to the burst limit. The epoll keeps
+    /// reporting the listener as long as connections are waiting, so the rest get accepted after the next poll.
+    fn accept_clients(&mut self, listener: ListenerId) {
+        for _ in 0 .. self.max_accept_burst {
+            let Some(socket) = self.sockets.get(usize::from(listener.0)) else { return };
+            match socket.accept() {
+                Ok((channel, credentials)) => self.add_client(channel, credentials),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    warn!("Failed to accept a client on {listener:?}: {err}");
+                    return;
+                },
+            }
+        }
+    }
+
+    /// Starts serving a freshly accepted channel, or turns it away if there are too many clients already.
+    fn add_client(&mut self, channel: StreamChannel, credentials: Credentials) {
+        if self.clients.len() >= self.max_clients {
+            warn!("Rejecting a client with pid {}: too many clients.", credentials.pid);
+            let mut channel = channel;
+            let busy = Event { serial: None, msg: EventMsg::ServerBusy };
+            if let Err(err) = channel.send_event(busy, Vec::new()) {
+                warn!("Failed to tell a rejected client that the server is busy: {err}");
+            }
+            return;
+        }
+
+        match channel.peer_addr() {
+            Ok(Some(address)) => info!("Accepted a client with pid {} from {address}.", credentials.pid),
+            Ok(None) => info!("Accepted a client with pid {} from an unnamed socket.", credentials.pid),
+            Err(err) => info!("Accepted a client with pid {} from an unknown address: {err}", credentials.pid),
+        }
+        let mut client = Client::new(channel, credentials);
+        client.set_rate_limit(self.rate_limit);
+        client.set_coalescing(self.coalescing);
+        self.clients.insert(&self.epoll, client)
+            .expect("Failed to register a new client with the epoll!");
+    }
+
+    /// Sends an event that is not a reply to any request to every client that has announced itself and is
+    /// subscribed to the event's topic. Events without a topic go to every client that has announced itself.
+    fn broadcast(&mut self, msg: EventMsg) -> anyhow::Result<()> {
+        let topic = msg.topic();
+        let kind = msg.is_coalescable().then(|| std::mem::discriminant(&msg));
+        let packet = Packet::try_from((Event { serial: None, msg }, Vec::new()))?;
+        self.broadcast_packet(packet, topic, kind)
+    }
+
+    /// Sends the same packet to every client that has announced itself and, if a topic is given, subscribed to
+    /// it. Clients to which the packet cannot be written are removed. Packets with file descriptors cannot be
+    /// broadcast, because the file descriptors would need to be duplicated for every client.
+    ///
+    /// Clients that are still waiting for earlier packets to be sent only get the packet queued, so that a slow
+    /// client costs no more than a copy of the packet. It gets sent once the epoll reports them writable. If
+    /// `kind` is given, clients with coalescing enabled keep only the latest packet of that kind queued.
+    fn broadcast_packet(
+        &mut self, packet: Packet, topic: Option<&str>, kind: Option<Discriminant<EventMsg>>,
+    ) -> anyhow::Result<()> {
+        if !packet.fds.is_empty() {
+            bail!("Cannot broadcast a packet with file descriptors attached.");
+        }
+
+        let mut broken_clients = Vec::new();
+        let recipients = self.clients.iter_mut()
+            .filter(|(_, client)| client.state() == ClientState::Announced)
+            .filter(|(_, client)| topic.is_none_or(|topic| client.is_subscribed(topic)));
+        for (id, client) in recipients {
+            let copy = Packet { data: packet.data.clone(), kind: packet.kind, fds: Vec::new() };
+            if let Err(err) = client.send_unsolicited(copy, kind) {
+                broken_clients.push((id, err));
+            }
+        }
+
+        for (id, err) in broken_clients {
+            self.disconnect_client(id, format_args!("failed to broadcast: {err}"));
+        }
+
+        let ids = self.clients.ids();
+        for id in ids {
+            self.update_write_interest(id);
+        }
+
+        Ok(())
+    }
+
+    /// Sends an input event to a single client, typically the one that has the focus. Motion gets coalesced
+    /// like broadcast events if the client is backed up and coalescing is enabled. A client to which the event
+    /// cannot be written is removed.
+    fn deliver_input(&mut self, id: ClientId, msg: EventMsg) -> anyhow::Result<()> {
+        if !msg.is_input() {
+            bail!("Only input events can be delivered, not {msg:?}.");
+        }
+        let Some(client) = self.clients.get_mut(id) else {
+            bail!("Cannot deliver input to client {id:?}, which is not connected.");
+        };
+        let kind = msg.is_coalescable().then(|| std::mem::discriminant(&msg));
+        let packet = Packet::try_from((Event { serial: None, msg }, Vec::new()))?;
+        if let Err(err) = client.send_unsolicited(packet, kind) {
+            self.disconnect_client(id, format_args!("failed to deliver input: {err}"));
+            return Ok(());
+        }
+        self.update_write_interest(id);
+        Ok(())
+    }
+
+    /// Makes sure that the epoll reports when a client's channel becomes writable if and only if the client has
+    /// packets that are waiting to be sent. Must be called after writing to a client.
+    ///
+    /// Clients whose queue has grown beyond the limit are not keeping up with what we send them; they get
+    /// disconnected rather than letting them make us buffer an unbounded amount of data.
+    fn update_write_interest(&mut self, id: ClientId) {
+        let Some(client) = self.clients.get_mut(id) else { return };
+        if client.queued_bytes() > self.max_queued_bytes {
+            self.disconnect_client(id, "not reading what we send");
+            return;
+        }
+        let wants_write = client.wants_write();
+        if wants_write == client.write_interest() {
+            return;
+        }
+
+        self.epoll.modify(&*client, PollId::Client(id), wants_write)
+            .expect("Failed to modify a client's registration with the epoll!");
+        client.set_write_interest(wants_write);
+    }
+
+    /// Sends the client list to the client for every time it asked for it since this was last called. Clients
+    /// that do not run as the admin user get an error instead and are disconnected, because the list reveals
+    /// the processes of other users.
+    fn answer_client_list_requests(&mut self, id: ClientId) {
+        let Some(client) = self.clients.get_mut(id) else { return };
+        let serials = client.take_client_list_requests();
+        if serials.is_empty() {
+            return;
+        }
+        let uid = client.uid();
+        if uid != self.admin_uid {
+            let message = "Only the user running the server may list the clients.".to_owned();
+            let msg = EventMsg::Error { code: ERROR_PERMISSION_DENIED, message };
+            if let Err(err) = client.send_event(Event { serial: Some(serials[0]), msg }, Vec::new()) {
+                warn!("Failed to tell client {id:?} that it may not list the clients: {err}");
+            }
+            self.disconnect_client(id, format_args!("user {uid} tried to list the clients"));
+            return;
+        }
+
+        let snapshot = self.clients.snapshot();
+        let client = self.clients.get_mut(id).unwrap();
+        for serial in serials {
+            let event = Event { serial: Some(serial), msg: EventMsg::ClientList { clients: snapshot.clone() } };
+            // A broken channel will be noticed and cleaned up by the next read.
+            if let Err(err) = client.send_event(event, Vec::new()) {
+                warn!("Failed to send the client list to client {id:?}: {err}");
+            }
+        }
+    }
+
+    /// Removes a client from the epoll and the client list, then closes its channel. Every disconnect goes
+    /// through here. Does nothing if the client has already been removed. If the client has a session, it can
+    /// be resumed during the grace period.
+    fn disconnect_client(&mut self, id: ClientId, reason: impl std::fmt::Display) {
+        let Some(mut client) = self.clients.remove(&self.epoll, id) else { return };
+        info!("Disconnected client {id:?}: {reason}.");
+        // Dropping the channel would wait for the client to take what is still queued, stalling every other
+        // client in the meantime.
+        let discarded = client.channel_mut().discard_pending_writes();
+        if discarded > 0 {
+            warn!("Discarded {discarded} bytes that client {id:?} did not read.");
+        }
+        match client.into_session(id) {
+            Some((token, state)) => self.sessions.suspend(token, state),
+            None => self.handler.on_disconnect(id),
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    // Under systemd socket activation, systemd owns the socket and its file.
+    let socket = match StreamSocket::from_activation() {
+        Some(socket) => {
+            info!("Using the socket passed by systemd.");
+            socket
+        },
+        None => open_socket(),
+    };
+
+    let mut program = Program::new(socket);
+    program.install_signal_handlers().expect("Failed to install signal handlers.");
+
+    info!("Socket created!");
+    program.run();
+
+    // Dropping the program closes the socket and removes its file.
+}
+
+fn open_socket() -> StreamSocket {
+    let path = libuio::socket::default_socket_path();
     let dir = path.parent().expect("UIO socket path does not lie in a directory.");
     if !dir.exists() {
         std::fs::create_dir_all(dir).expect("Failed to create the directory containing the UIO socket.");
     }
 
-    // Create the actual socket.
-    let socket = StreamSocket::open(path.to_owned())
+    // Create the actual socket. This replaces leftovers of servers that did not shut down cleanly, but fails
+    // if another server is still running.
+    StreamSocket::open(path)
         .context("Failed to create a socket")
-        .unwrap();
-
-    let epoll: Epoll<PollId> = Epoll::new().expect("Failed to create an epoll instance.");
-    epoll.add(&socket, PollId::Socket).expect("Failed to add socket to epoll.");
-
-    // Identifies clients by the file descriptor of their channel.
-    //
-    // Using file descriptors for identification is handy because the kernel automatically manages them for us:
-    // as long as a client with an open channel is in this hashmap, we are sure that its file descriptor is still
-    // valid. When a client gets closed, its file descriptor can be reused, preventing some DoS attack that tries
-    // to overflow our ID count by connecting and disconnecting a bazillion times.
-    let mut clients: HashMap<RawFd, Client> = HashMap::new();
-
-    println!("Socket created!");
-    loop {
-        let events = epoll.poll()
-            .expect("Failed to poll from the epoll.");
-        println!("Received {} events.", events.len());
-
-        for event in events {
-            match event {
-                epoll::Message::Ready(key) => match key {
-                    PollId::Client(raw_fd) => {
-                        println!("Client ready.");
-                        let Some(client) = clients.get_mut(&raw_fd) else { continue };
-                        crate::handler::handle_ready_client(client);
-                    },
-                    PollId::Socket => {
-                        println!("Socket ready.");
-                        let channel = socket.accept().expect("Failed to accept incoming channel.");
-                        let client = Client::new(channel);
-                        let raw_fd = client.as_raw_fd();
-
-                        epoll.add(&client, PollId::Client(raw_fd))
-                            .expect("Failed to register a new client with the epoll!");
-
-                        let old_client_using_fd = clients.insert(raw_fd, client);
-                        
-                        // It should be impossible that there was another client using the same file descriptor,
-                        // because the file descriptor of a client cannot be closed without dropping the Client
-                        // structure, and if the Client is dropped, then it can no longer occupy a spot in the
-                        // HashMap. I am still asserting that anyway, because if that logic were to somehow fail,
-                        // there'd probably be a security hole.
-                        assert!(old_client_using_fd.is_none());
-                    },
-                },
-                epoll::Message::Broken(key) | epoll::Message::Hup(key) => match key {
-                    PollId::Client(raw_fd) => {
-                        println!("Client broken.");
-                        let Some(client) = clients.remove(&raw_fd) else { continue };
-                        epoll.delete(client.channel().as_fd())
-                            .expect("Failed to remove a client from the epoll!");
-                    },
-                    PollId::Socket => panic!("Socket broken!"),
-                },
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+
+    use libuio::message::{AnnounceMsg, ClientInfo, HelloMsg, RequestMsg, TOPIC_CLIENTS, TOPIC_SURFACES};
+    use libuio::socket::StreamSocketBuilder;
+    use rustix::event::{PollFd, PollFlags};
+
+    use super::*;
+    use crate::test_utils::{receive_events, send_request, unique_socket_path};
+
+    /// Creates a program without a tick, so that every step is caused by the test.
+    fn test_program() -> (Program, PathBuf) {
+        let path = unique_socket_path();
+        let mut program = Program::new(StreamSocket::open(path.clone()).unwrap());
+        program.set_tick_interval(None);
+        (program, path)
+    }
+
+    /// Connects a new client to the program and lets it go through the whole handshake.
+    fn connect_announced(program: &mut Program, path: &Path) -> StreamChannel {
+        let mut channel = StreamChannel::open(path).unwrap();
+        program.step();
+
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, 0, RequestMsg::Announce(AnnounceMsg { name: "Test client".to_owned() }));
+        program.step();
+        receive_events(&mut channel);
+        channel
+    }
+
+    fn is_readable(channel: &StreamChannel) -> bool {
+        let mut to_poll = [PollFd::new(channel, PollFlags::IN)];
+        rustix::event::poll(&mut to_poll, 0).unwrap() > 0
+    }
+
+    #[test]
+    fn goodbye_removes_client() {
+        let (mut program, path) = test_program();
+
+        let mut channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        assert_eq!(program.clients.len(), 1);
+
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, 0, RequestMsg::Goodbye { reason: Some("Testing".to_owned()) });
+        program.step();
+        assert!(program.clients.is_empty());
+    }
+
+    #[test]
+    fn reconnecting_client_gets_fresh_id() {
+        let (mut program, path) = test_program();
+        let first = connect_announced(&mut program, &path);
+        let first_id = program.clients.ids()[0];
+        drop(first);
+        program.step();
+        assert!(program.clients.is_empty());
+
+        let _second = connect_announced(&mut program, &path);
+        let second_id = program.clients.ids()[0];
+        assert_eq!(second_id.index, first_id.index);
+        assert!(program.clients.get(first_id).is_none());
+    }
+
+    #[test]
+    fn closed_channel_removes_client() {
+        let (mut program, path) = test_program();
+        let channel = connect_announced(&mut program, &path);
+        assert_eq!(program.clients.len(), 1);
+
+        drop(channel);
+        program.step();
+        assert!(program.clients.is_empty());
+    }
+
+    #[test]
+    fn clients_are_accepted_on_every_listener() {
+        let (mut program, first_path) = test_program();
+        let second_path = unique_socket_path();
+        let listener = program.add_listener(StreamSocket::open(second_path.clone()).unwrap()).unwrap();
+        assert_eq!(listener, ListenerId(1));
+
+        let _first = connect_announced(&mut program, &first_path);
+        let _second = connect_announced(&mut program, &second_path);
+        assert_eq!(program.clients.len(), 2);
+        assert!(program.clients.values().all(|client| client.state() == ClientState::Announced));
+    }
+
+    #[test]
+    fn reconnecting_client_resumes_its_session() {
+        let (mut program, path) = test_program();
+        let mut channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, 1, RequestMsg::Announce(AnnounceMsg { name: "Resumable".to_owned() }));
+        send_request(&mut channel, 2, RequestMsg::Subscribe { topics: vec![TOPIC_CLIENTS.to_owned()] });
+        program.step();
+        let token = receive_events(&mut channel).into_iter()
+            .find_map(|event| match event.msg {
+                EventMsg::SessionToken { token } => Some(token),
+                _ => None,
+            })
+            .expect("The server did not hand out a session token.");
+
+        drop(channel);
+        program.step();
+        assert!(program.clients.is_empty());
+
+        let mut channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, 1, RequestMsg::Resume { token });
+        program.step();
+        let events = receive_events(&mut channel);
+        assert!(matches!(events[..], [
+            Event { msg: EventMsg::Hello(_), .. },
+            Event { serial: Some(1), msg: EventMsg::AnnounceAccepted },
+        ]));
+        let (_, client) = program.clients.by_name("Resumable").unwrap();
+        assert_eq!(client.state(), ClientState::Announced);
+        assert!(program.sessions.is_empty());
+
+        // The subscriptions survive the reconnect.
+        program.broadcast(EventMsg::ClientList { clients: Vec::new() }).unwrap();
+        assert!(matches!(receive_events(&mut channel)[..], [Event { serial: None, msg: EventMsg::ClientList { .. } }]));
+    }
+
+    #[test]
+    fn handler_follows_the_client_across_a_resumed_session() {
+        #[derive(Debug, PartialEq)]
+        enum Hook {
+            Resume(ClientId, ClientId),
+            Disconnect(ClientId),
+        }
+        struct RecordingHandler(Rc<RefCell<Vec<Hook>>>);
+        impl RequestHandler for RecordingHandler {
+            fn on_request(&mut self, _client_id: ClientId, request: RequestMsg, _fds: Vec<OwnedFd>) -> Vec<EventMsg> {
+                match request {
+                    RequestMsg::Announce(_) => vec![EventMsg::AnnounceAccepted],
+                    _ => Vec::new(),
+                }
+            }
+
+            fn on_resume(&mut self, old_id: ClientId, new_id: ClientId) {
+                self.0.borrow_mut().push(Hook::Resume(old_id, new_id));
+            }
+
+            fn on_disconnect(&mut self, client_id: ClientId) {
+                self.0.borrow_mut().push(Hook::Disconnect(client_id));
+            }
+        }
+
+        let path = unique_socket_path();
+        let hooks = Rc::new(RefCell::new(Vec::new()));
+        let handler = Box::new(RecordingHandler(hooks.clone()));
+        let mut program = Program::with_handler(StreamSocket::open(path.clone()).unwrap(), handler);
+        program.set_tick_interval(None);
+
+        let mut channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, 1, RequestMsg::Announce(AnnounceMsg { name: "Resumable".to_owned() }));
+        program.step();
+        let old_id = program.clients.ids()[0];
+        let token = receive_events(&mut channel).into_iter()
+            .find_map(|event| match event.msg {
+                EventMsg::SessionToken { token } => Some(token),
+                _ => None,
+            })
+            .unwrap();
+
+        // A suspended session may still be resumed, so the handler is not told about the disconnect yet.
+        drop(channel);
+        program.step();
+        assert!(hooks.borrow().is_empty());
+
+        let mut channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        send_request(&mut channel, 1, RequestMsg::Resume { token });
+        program.step();
+        let new_id = program.clients.ids()[0];
+        assert_ne!(old_id, new_id);
+        assert_eq!(*hooks.borrow(), vec![Hook::Resume(old_id, new_id)]);
+
+        // Once the session expires, the client is gone for good.
+        program.set_session_grace_period(Duration::ZERO);
+        drop(channel);
+        program.step();
+        program.on_tick();
+        assert_eq!(*hooks.borrow(), vec![Hook::Resume(old_id, new_id), Hook::Disconnect(new_id)]);
+    }
+
+    #[test]
+    fn goodbye_ends_the_session() {
+        let (mut program, path) = test_program();
+        let mut channel = connect_announced(&mut program, &path);
+        send_request(&mut channel, 2, RequestMsg::Goodbye { reason: None });
+        program.step();
+        assert!(program.clients.is_empty());
+        assert!(program.sessions.is_empty());
+    }
+
+    #[test]
+    fn tick_follows_the_configured_interval() {
+        let (mut program, _path) = test_program();
+        program.set_tick_interval(Some(Duration::from_millis(20)));
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(200) {
+            program.step();
+        }
+        // Ten ticks are due; leave some room for a slow machine.
+        assert!((7..=11).contains(&program.ticks), "{} ticks in 200ms", program.ticks);
+    }
+
+    #[test]
+    fn disconnected_client_leaves_the_epoll_and_the_client_list() {
+        let (mut program, path) = test_program();
+        let mut channel = connect_announced(&mut program, &path);
+        let id = program.clients.ids()[0];
+        // Keeps the server's end open, so the epoll would keep reporting it if it were still registered.
+        let server_end = rustix::io::dup(program.clients.get(id).unwrap().channel()).unwrap();
+
+        program.disconnect_client(id, "testing");
+        assert!(program.clients.get(id).is_none());
+
+        send_request(&mut channel, 5, RequestMsg::ListClients);
+        assert!(program.epoll.poll_timeout(Some(Duration::ZERO)).unwrap().is_empty());
+        drop(server_end);
+    }
+
+    #[test]
+    fn waker_ends_a_step() {
+        let (mut program, _path) = test_program();
+        let waker = program.waker();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            waker.wake().unwrap();
+        });
+        // Would block forever without the wakeup, since nothing else is going to happen.
+        program.step();
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn idle_client_gets_removed() {
+        let (mut program, path) = test_program();
+        program.set_idle_timeout(Some(Duration::from_millis(50)));
+        let _channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        assert_eq!(program.clients.len(), 1);
+
+        let start = Instant::now();
+        while !program.clients.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "The idle client was never removed.");
+            program.step();
+        }
+    }
+
+    #[test]
+    fn unannounced_client_gets_removed() {
+        let (mut program, path) = test_program();
+        program.set_handshake_timeout(Some(Duration::from_millis(50)));
+        let mut channel = StreamChannel::open(&path).unwrap();
+        program.step();
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        program.step();
+        assert_eq!(program.clients.len(), 1);
+
+        let start = Instant::now();
+        while !program.clients.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "The unannounced client was never removed.");
+            program.step();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn announced_client_outlives_the_handshake_timeout() {
+        let (mut program, path) = test_program();
+        program.set_handshake_timeout(Some(Duration::from_millis(20)));
+        let _channel = connect_announced(&mut program, &path);
+
+        std::thread::sleep(Duration::from_millis(30));
+        program.remove_unannounced_clients();
+        assert_eq!(program.clients.len(), 1);
+    }
+
+    #[test]
+    fn unresponsive_client_gets_removed() {
+        let (mut program, path) = test_program();
+        program.set_heartbeat(Some(Duration::from_millis(20)), 2);
+        let mut channel = connect_announced(&mut program, &path);
+
+        let start = Instant::now();
+        let mut pings = 0;
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(5), "The unresponsive client was never removed.");
+            program.step();
+            if program.clients.is_empty() {
+                break;
+            }
+            pings += receive_events(&mut channel).iter()
+                .filter(|event| matches!(event.msg, EventMsg::Ping { .. }))
+                .count();
+        }
+        assert_eq!(pings, 2);
+    }
+
+    #[test]
+    fn responsive_client_is_kept() {
+        let (mut program, path) = test_program();
+        program.set_heartbeat(Some(Duration::from_millis(20)), 2);
+        let mut channel = connect_announced(&mut program, &path);
+
+        let mut pings = 0;
+        while pings < 5 {
+            program.step();
+            for event in receive_events(&mut channel) {
+                if let EventMsg::Ping { nonce } = event.msg {
+                    send_request(&mut channel, 0, RequestMsg::Pong { nonce });
+                    pings += 1;
+                }
             }
         }
+        assert_eq!(program.clients.len(), 1);
+    }
+
+    #[test]
+    fn broadcast_reaches_all_clients() {
+        let (mut program, path) = test_program();
+        let mut channels: Vec<StreamChannel> = (0..3)
+            .map(|_| connect_announced(&mut program, &path))
+            .collect();
+
+        program.broadcast(EventMsg::AnnounceAccepted).unwrap();
+
+        for channel in &mut channels {
+            let events = receive_events(channel);
+            assert!(matches!(events[..], [Event { serial: None, msg: EventMsg::AnnounceAccepted }]));
+        }
+    }
+
+    #[test]
+    fn broadcast_removes_broken_clients() {
+        let (mut program, path) = test_program();
+        let mut alive = connect_announced(&mut program, &path);
+        let dead = connect_announced(&mut program, &path);
+        drop(dead);
+
+        program.broadcast(EventMsg::AnnounceAccepted).unwrap();
+
+        assert_eq!(receive_events(&mut alive).len(), 1);
+        assert_eq!(program.clients.len(), 1);
+    }
+
+    #[test]
+    fn broadcast_does_not_wait_for_a_blocked_client() {
+        let (mut program, path) = test_program();
+        let mut fast = connect_announced(&mut program, &path);
+        let mut blocked = connect_announced(&mut program, &path);
+        let blocked_id = program.clients.ids()[1];
+
+        // Fill the socket of the blocked client, so that its queue is not empty.
+        program.clients.get_mut(blocked_id).unwrap().channel_mut()
+            .write_packet(Packet::new(vec![0; 1024 * 1024], Vec::new())).unwrap();
+        program.update_write_interest(blocked_id);
+        assert!(program.clients.get(blocked_id).unwrap().wants_write());
+
+        program.broadcast(EventMsg::Shutdown).unwrap();
+        assert!(matches!(receive_events(&mut fast)[..], [Event { serial: None, msg: EventMsg::Shutdown }]));
+
+        // The blocked client gets the broadcast after the big packet, once it starts reading.
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            while is_readable(&blocked) {
+                received.extend(blocked.read_packets().unwrap());
+            }
+            if program.clients.get(blocked_id).unwrap().wants_write() {
+                program.step();
+            }
+        }
+        let broadcast: (Event, Vec<_>) = received.pop().unwrap().try_into().unwrap();
+        assert!(matches!(broadcast.0, Event { serial: None, msg: EventMsg::Shutdown }));
+    }
+
+    #[test]
+    fn coalescing_keeps_only_the_latest_event_for_a_blocked_client() {
+        let (mut program, path) = test_program();
+        program.set_coalescing(true);
+        let mut blocked = connect_announced(&mut program, &path);
+        send_request(&mut blocked, 5, RequestMsg::Subscribe { topics: vec![TOPIC_CLIENTS.to_owned()] });
+        program.step();
+        let id = program.clients.ids()[0];
+
+        program.clients.get_mut(id).unwrap().channel_mut()
+            .write_packet(Packet::new(vec![0; 1024 * 1024], Vec::new())).unwrap();
+        program.update_write_interest(id);
+        for pid in 0 .. 100 {
+            let clients = vec![ClientInfo { id: 0, name: None, pid, connected_for: Duration::ZERO }];
+            program.broadcast(EventMsg::ClientList { clients }).unwrap();
+        }
+        assert_eq!(program.clients.get(id).unwrap().coalesced_count(), 1);
+
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            while is_readable(&blocked) {
+                received.extend(blocked.read_packets().unwrap());
+            }
+            if program.clients.get(id).unwrap().wants_write() {
+                program.step();
+            }
+        }
+        let (event, _): (Event, _) = received.pop().unwrap().try_into().unwrap();
+        let EventMsg::ClientList { clients } = event.msg else { panic!("Got {event:?}") };
+        assert_eq!(clients[0].pid, 99);
+        assert!(!is_readable(&blocked));
+    }
+
+    #[test]
+    fn broadcast_only_reaches_subscribers_of_the_topic() {
+        let (mut program, path) = test_program();
+        let mut clients_subscriber = connect_announced(&mut program, &path);
+        let mut surfaces_subscriber = connect_announced(&mut program, &path);
+        send_request(&mut clients_subscriber, 5, RequestMsg::Subscribe { topics: vec![TOPIC_CLIENTS.to_owned()] });
+        send_request(&mut surfaces_subscriber, 5, RequestMsg::Subscribe { topics: vec![TOPIC_SURFACES.to_owned()] });
+        program.step();
+
+        program.broadcast(EventMsg::ClientList { clients: Vec::new() }).unwrap();
+        assert!(matches!(receive_events(&mut clients_subscriber)[..], [Event { msg: EventMsg::ClientList { .. }, .. }]));
+        assert!(receive_events(&mut surfaces_subscriber).is_empty());
+
+        // Surface updates carry file descriptors, which cannot be broadcast, so another event stands in for one.
+        let surface_packet = Packet::try_from((Event { serial: None, msg: EventMsg::Shutdown }, Vec::new())).unwrap();
+        program.broadcast_packet(surface_packet, Some(TOPIC_SURFACES), None).unwrap();
+        assert!(receive_events(&mut clients_subscriber).is_empty());
+        assert_eq!(receive_events(&mut surfaces_subscriber).len(), 1);
+
+        // Events without a topic reach everyone.
+        program.broadcast(EventMsg::Shutdown).unwrap();
+        assert_eq!(receive_events(&mut clients_subscriber).len(), 1);
+        assert_eq!(receive_events(&mut surfaces_subscriber).len(), 1);
+    }
+
+    #[test]
+    fn requests_beyond_the_burst_wait_for_the_next_tick() {
+        let (mut program, path) = test_program();
+        let mut channel = connect_announced(&mut program, &path);
+        program.set_rate_limit(Some(RateLimit { packets_per_second: 1000, burst: 3 }), None);
+
+        for serial in 0 .. 5 {
+            send_request(&mut channel, serial, RequestMsg::GetStats);
+        }
+        program.step();
+        assert_eq!(receive_events(&mut channel).len(), 3);
+        assert!(program.clients.values().all(Client::is_throttled));
+
+        std::thread::sleep(Duration::from_millis(10));
+        program.on_tick();
+        let serials: Vec<_> = receive_events(&mut channel).into_iter().map(|event| event.serial).collect();
+        assert_eq!(serials, [Some(3), Some(4)]);
+        assert_eq!(program.clients.len(), 1);
+    }
+
+    #[test]
+    fn client_that_keeps_exceeding_its_rate_limit_gets_removed() {
+        let (mut program, path) = test_program();
+        let mut channel = connect_announced(&mut program, &path);
+        program.set_rate_limit(Some(RateLimit { packets_per_second: 1, burst: 1 }), Some(1));
+
+        for serial in 0 .. 5 {
+            send_request(&mut channel, serial, RequestMsg::GetStats);
+        }
+        program.step();
+        program.on_tick();
+        assert_eq!(program.clients.len(), 1);
+        program.on_tick();
+        assert!(program.clients.is_empty());
+    }
+
+    #[test]
+    fn key_event_reaches_the_client_it_is_delivered_to() {
+        let (mut program, path) = test_program();
+        let mut focused = connect_announced(&mut program, &path);
+        let mut other = connect_announced(&mut program, &path);
+        let id = program.clients.ids()[0];
+        let fd = program.clients.get(id).unwrap().as_raw_fd();
+        assert_eq!(program.clients.by_fd(fd).map(|(id, _)| id), Some(id));
+
+        program.deliver_input(id, EventMsg::Key { keycode: 30, state: true }).unwrap();
+
+        let events = receive_events(&mut focused);
+        assert!(matches!(events[..], [Event { serial: None, msg: EventMsg::Key { keycode: 30, state: true } }]));
+        assert!(receive_events(&mut other).is_empty());
+        assert!(program.deliver_input(id, EventMsg::Shutdown).is_err());
+    }
+
+    #[test]
+    fn client_list_contains_all_clients() {
+        let (mut program, path) = test_program();
+        let mut asking = connect_announced(&mut program, &path);
+        let _other = connect_announced(&mut program, &path);
+
+        send_request(&mut asking, 7, RequestMsg::ListClients);
+        program.step();
+
+        let events = receive_events(&mut asking);
+        let [Event { serial: Some(7), msg: EventMsg::ClientList { clients } }] = &events[..] else {
+            panic!("Expected a client list, got {events:?}");
+        };
+        let expected_ids: Vec<u64> = program.clients.ids().into_iter().map(u64::from).collect();
+        assert_eq!(clients.iter().map(|info| info.id).collect::<Vec<_>>(), expected_ids);
+        for info in clients {
+            assert_eq!(info.name.as_deref(), Some("Test client"));
+            assert_eq!(info.pid, std::process::id() as i32);
+        }
+        // The asking client connected first, so it has been connected for at least as long as the other one.
+        assert!(clients[0].connected_for >= clients[1].connected_for);
+    }
+
+    #[test]
+    fn client_of_another_user_may_not_list_the_clients() {
+        let (mut program, path) = test_program();
+        let mut asking = connect_announced(&mut program, &path);
+        program.admin_uid += 1;
+
+        send_request(&mut asking, 7, RequestMsg::ListClients);
+        program.step();
+
+        let events = receive_events(&mut asking);
+        assert!(matches!(events[..], [Event { serial: Some(7), msg: EventMsg::Error { code: ERROR_PERMISSION_DENIED, .. } }]));
+        assert!(program.clients.is_empty());
+    }
+
+    #[test]
+    fn connection_storm_is_accepted_in_bursts() {
+        let path = unique_socket_path();
+        let socket = StreamSocketBuilder::new().backlog(128).open(path.clone()).unwrap();
+        let mut program = Program::new(socket);
+        program.set_tick_interval(None);
+        program.set_max_accept_burst(64);
+        let mut existing = connect_announced(&mut program, &path);
+
+        let _storm: Vec<StreamChannel> = (0 .. 100).map(|_| StreamChannel::open(&path).unwrap()).collect();
+        send_request(&mut existing, 9, RequestMsg::GetStats);
+        program.step();
+
+        // The existing client gets served in the same step as the first burst.
+        assert_eq!(program.clients.len(), 1 + 64);
+        assert!(matches!(receive_events(&mut existing)[..], [Event { serial: Some(9), msg: EventMsg::Stats { .. } }]));
+
+        program.step();
+        assert_eq!(program.clients.len(), 1 + 100);
+    }
+
+    #[test]
+    fn clients_beyond_the_limit_are_rejected() {
+        let (mut program, path) = test_program();
+        program.set_max_clients(2);
+
+        let mut channels: Vec<StreamChannel> = (0 .. 3).map(|_| {
+            let channel = StreamChannel::open(&path).unwrap();
+            program.step();
+            channel
+        }).collect();
+        assert_eq!(program.clients.len(), 2);
+
+        let rejected = channels.last_mut().unwrap();
+        assert!(matches!(receive_events(rejected)[..], [Event { serial: None, msg: EventMsg::ServerBusy }]));
+        assert!(rejected.read_packets().is_err());
+    }
+
+    #[test]
+    fn slow_consumer_gets_removed() {
+        let (mut program, path) = test_program();
+        program.set_max_queued_bytes(256 * 1024);
+        let _channel = connect_announced(&mut program, &path);
+
+        // The client never reads, so the socket fills up and the rest piles up in the queue.
+        let mut broadcasts = 0;
+        while !program.clients.is_empty() {
+            assert!(broadcasts < 1000, "The slow client was never removed.");
+            program.broadcast_packet(Packet::new(vec![0; 64 * 1024], Vec::new()), None, None).unwrap();
+            broadcasts += 1;
+        }
+    }
+
+    #[test]
+    fn shutdown_does_not_wait_for_a_client_that_never_reads() {
+        let (mut program, path) = test_program();
+        let _channel = connect_announced(&mut program, &path);
+        let id = program.clients.ids()[0];
+
+        program.clients.get_mut(id).unwrap().channel_mut()
+            .write_packet(Packet::new(vec![0; 1024 * 1024], Vec::new())).unwrap();
+        program.update_write_interest(id);
+
+        let start = Instant::now();
+        program.shutdown();
+        assert!(program.clients.is_empty());
+        drop(program);
+        assert!(start.elapsed() < SHUTDOWN_FLUSH_TIMEOUT + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn queued_packets_get_flushed_when_writable() {
+        let (mut program, path) = test_program();
+        let mut channel = connect_announced(&mut program, &path);
+        let id = program.clients.ids()[0];
+
+        // Write more than the socket can hold, so the packet stays partially queued.
+        let big_packet = Packet::new(vec![7; 1024 * 1024], Vec::new());
+        program.clients.get_mut(id).unwrap().channel_mut().write_packet(big_packet).unwrap();
+        program.update_write_interest(id);
+        assert!(program.clients.get(id).unwrap().write_interest());
+
+        let mut received = Vec::new();
+        while received.is_empty() {
+            // Drain everything the socket currently holds, so the server's side becomes writable again.
+            while is_readable(&channel) {
+                received.extend(channel.read_packets().unwrap());
+            }
+            if program.clients.get(id).unwrap().wants_write() {
+                program.step();
+            }
+        }
+
+        assert_eq!(received[0].data.len(), 1024 * 1024);
+        assert!(!program.clients.get(id).unwrap().write_interest());
     }
 }
@@ -1,42 +1,156 @@
-use std::os::fd::RawFd;
+use crate::state::ClientId;
+
+/// Identifies one of the sockets that the server accepts clients on.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ListenerId(pub u16);
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PollId {
-    Client(RawFd),
-    Socket,
+    Client(ClientId),
+    Socket(ListenerId),
+    /// The read end of the pipe through which signal handlers wake up the main loop.
+    Signal,
+    /// The timerfd that drives the periodic tick.
+    Timer,
+    /// The eventfd through which other threads wake up the main loop.
+    Wakeup,
 }
 
-// When converting PollId <=> u64, the four biggest bytes denote the enum variant, and the smallest four bytes
-// denote the enum value, if any.
-const POLL_TAG_MASK: u64   = 0xffff0000;
-const POLL_VALUE_MASK: u64 = 0x0000ffff;
-const POLL_CLIENT_TAG: u64 = 0x00010000;
-const POLL_SOCKET_TAG: u64 = 0x00020000;
+// When converting PollId <=> u64, the two biggest bytes denote the enum variant, and the smallest six bytes
+// denote the enum value, if any. A ClientId is stored with its generation in bytes 4-5 and its index in
+// bytes 0-3. A ListenerId is stored in bytes 0-1.
+const POLL_TAG_MASK: u64   = 0xffff_0000_0000_0000;
+const POLL_VALUE_MASK: u64 = 0x0000_ffff_ffff_ffff;
+const POLL_CLIENT_TAG: u64 = 0x0001_0000_0000_0000;
+const POLL_SOCKET_TAG: u64 = 0x0002_0000_0000_0000;
+const POLL_SIGNAL_TAG: u64 = 0x0003_0000_0000_0000;
+const POLL_TIMER_TAG: u64  = 0x0004_0000_0000_0000;
+const POLL_WAKEUP_TAG: u64 = 0x0005_0000_0000_0000;
 
-impl Into<u64> for PollId {
-    fn into(self) -> u64 {
-        match self {
-            PollId::Client(value) => POLL_CLIENT_TAG | (value as u64),
-            PollId::Socket => POLL_SOCKET_TAG,
+impl From<PollId> for u64 {
+    fn from(id: PollId) -> u64 {
+        match id {
+            PollId::Client(ClientId { index, generation }) => {
+                POLL_CLIENT_TAG | ((generation as u64) << 32) | (index as u64)
+            },
+            PollId::Socket(ListenerId(index)) => POLL_SOCKET_TAG | (index as u64),
+            PollId::Signal => POLL_SIGNAL_TAG,
+            PollId::Timer => POLL_TIMER_TAG,
+            PollId::Wakeup => POLL_WAKEUP_TAG,
         }
     }
 }
 
 /// Returned when trying to construct a PollId from an u64 that couldn't possibly have been generated by poll_id.into().
-#[derive(Debug)]
-pub struct InvalidPollId;
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidPollId(pub u64);
+
+impl std::fmt::Display for InvalidPollId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#018x} is not a valid poll id", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPollId {}
 
 impl TryFrom<u64> for PollId {
     type Error = InvalidPollId;
 
     fn try_from(value: u64) -> Result<Self, Self::Error> {
         match value & POLL_TAG_MASK {
-            POLL_CLIENT_TAG => Ok(PollId::Client((value & POLL_VALUE_MASK) as _)),
-            POLL_SOCKET_TAG => match value & POLL_VALUE_MASK {
-                0 => Ok(PollId::Socket),
-                _ => Err(InvalidPollId),
+            POLL_CLIENT_TAG => Ok(PollId::Client(ClientId {
+                index: (value & 0xffff_ffff) as u32,
+                generation: ((value & POLL_VALUE_MASK) >> 32) as u16,
+            })),
+            POLL_SOCKET_TAG => match u16::try_from(value & POLL_VALUE_MASK) {
+                Ok(index) => Ok(PollId::Socket(ListenerId(index))),
+                Err(_) => Err(InvalidPollId(value)),
+            },
+            POLL_SIGNAL_TAG => match value & POLL_VALUE_MASK {
+                0 => Ok(PollId::Signal),
+                _ => Err(InvalidPollId(value)),
+            },
+            POLL_TIMER_TAG => match value & POLL_VALUE_MASK {
+                0 => Ok(PollId::Timer),
+                _ => Err(InvalidPollId(value)),
+            },
+            POLL_WAKEUP_TAG => match value & POLL_VALUE_MASK {
+                0 => Ok(PollId::Wakeup),
+                _ => Err(InvalidPollId(value)),
+            },
+            _ => Err(InvalidPollId(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(id: PollId) -> PollId {
+        PollId::try_from(u64::from(id)).unwrap()
+    }
+
+    #[test]
+    fn poll_ids_round_trip() {
+        for index in [0, 1, u16::MAX] {
+            let id = PollId::Socket(ListenerId(index));
+            assert!(round_trip(id) == id);
+        }
+        assert!(round_trip(PollId::Signal) == PollId::Signal);
+        assert!(round_trip(PollId::Timer) == PollId::Timer);
+        assert!(round_trip(PollId::Wakeup) == PollId::Wakeup);
+        for index in [0, 1, 0xffff, 0x1_0000, u32::MAX] {
+            for generation in [0, 1, 0xff, u16::MAX] {
+                let id = PollId::Client(ClientId { index, generation });
+                assert!(round_trip(id) == id);
+            }
+        }
+    }
+
+    /// Every value with a single bit set or cleared, plus the extremes, so that each bit of the encoding is
+    /// checked in isolation.
+    fn edge_values(bits: u32) -> Vec<u64> {
+        let max = u64::MAX >> (64 - bits);
+        let mut values = vec![0, max];
+        for bit in 0 .. bits {
+            values.extend([1 << bit, max & !(1 << bit)]);
+        }
+        values
+    }
+
+    #[test]
+    fn every_bit_of_the_ids_round_trips() {
+        for index in edge_values(32) {
+            for generation in edge_values(16) {
+                let id = PollId::Client(ClientId { index: index as u32, generation: generation as u16 });
+                assert!(round_trip(id) == id, "{:#018x} does not round trip", u64::from(id));
+            }
+        }
+        for index in 0 ..= u16::MAX {
+            let id = PollId::Socket(ListenerId(index));
+            assert!(round_trip(id) == id, "{:#018x} does not round trip", u64::from(id));
+        }
+    }
+
+    #[test]
+    fn different_ids_have_different_encodings() {
+        let mut ids = vec![PollId::Signal, PollId::Timer, PollId::Wakeup];
+        ids.extend(edge_values(16).into_iter().map(|index| PollId::Socket(ListenerId(index as u16))));
+        for index in edge_values(32) {
+            for generation in edge_values(16) {
+                ids.push(PollId::Client(ClientId { index: index as u32, generation: generation as u16 }));
             }
-            _ => Err(InvalidPollId),
+        }
+        let encodings: std::collections::HashSet<u64> = ids.iter().map(|&id| u64::from(id)).collect();
+        let distinct: std::collections::HashSet<PollId> = ids.into_iter().collect();
+        assert_eq!(encodings.len(), distinct.len());
+    }
+
+    #[test]
+    fn reserved_patterns_are_rejected() {
+        for value in [0, POLL_SOCKET_TAG | 0x1_0000, POLL_TIMER_TAG | 1, POLL_WAKEUP_TAG | 1, 0x0006_0000_0000_0000, 0xffff_0000_0000_0000, u64::MAX] {
+            assert_eq!(PollId::try_from(value).err(), Some(InvalidPollId(value)));
         }
     }
 }
@@ -1,36 +1,869 @@
 
-use libuio::socket::StreamChannel;
-use std::os::fd::{AsFd, AsRawFd};
+use libuio::channel::Channel;
+use libuio::message::{ClientInfo, ClientStats, EventMsg};
+use libuio::socket::{Credentials, Packet, StreamChannel};
+use log::warn;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem::Discriminant;
+use std::ops::Deref;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::time::{Duration, Instant};
 
-pub struct Client {
-    channel: StreamChannel,
+use crate::epoll::Epoll;
+use crate::poll::PollId;
+
+/// How far the client has progressed through the handshake.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ClientState {
+    /// The client has not sent its hello yet.
+    AwaitingHello,
+    /// The client has agreed on a protocol version, but has not identified itself.
+    Unknown,
+    /// The client has identified itself.
+    Announced,
+}
+
+/// A name that a client announced itself with. It is short enough to not bloat anything it gets copied into,
+/// and free of control characters, so it can be written to logs and terminals as is.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ClientName(String);
+
+impl ClientName {
+    /// Checks the name, returning the reason it is invalid if it is. Names may be at most `max_len` bytes long.
+    pub fn new(name: String, max_len: usize) -> Result<ClientName, String> {
+        if name.len() > max_len {
+            return Err(format!("The name is {} bytes long, but may be at most {max_len} bytes.", name.len()));
+        }
+        if name.chars().any(char::is_control) {
+            return Err("The name contains control characters.".to_owned());
+        }
+        Ok(ClientName(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
-impl AsFd for Client {
+impl std::fmt::Display for ClientName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A connected client. Generic over the channel so the handler can be tested without real sockets.
+pub struct Client<C = StreamChannel> {
+    channel: C,
+    state: ClientState,
+    /// The name the client announced itself with, if it has done so.
+    name: Option<ClientName>,
+    /// The credentials of the process on the other side of the channel at the time it connected.
+    credentials: Credentials,
+    /// Whether the epoll has been told to report when this client's channel becomes writable.
+    write_interest: bool,
+    /// When the client connected.
+    connected_at: Instant,
+    /// When the client last sent us something, or when it connected if it has not sent anything yet.
+    last_activity: Instant,
+    /// When the most recent ping was sent to the client, or when it connected if no ping has been sent yet.
+    last_ping: Instant,
+    /// The nonce of the most recent ping, if the client has not answered it yet.
+    pending_ping: Option<u64>,
+    /// How many pings in a row the client has not answered before the next ping was due.
+    missed_pongs: u32,
+    /// The token with which the client can resume its session after reconnecting, if it has one.
+    session_token: Option<u64>,
+    /// Serials of client list requests that have not been answered yet. Answering them takes a look at all
+    /// clients, which only the server can do once it is done handling this client.
+    client_list_requests: Vec<u32>,
+    /// The traffic exchanged over the channel. Updated by reading and writing through the client rather than
+    /// through its channel.
+    stats: ClientStats,
+    /// The topics of the events that the client wants to receive when they are sent to all clients.
+    subscriptions: HashSet<String>,
+    /// Limits how fast the client's packets get handled. `None` handles them as fast as they arrive.
+    rate_limit: Option<TokenBucket>,
+    /// Packets that have been read but not handled yet, because the client exceeded its rate limit.
+    deferred_packets: VecDeque<Packet>,
+    /// How many ticks in a row the client had packets deferred.
+    rate_violations: u32,
+    /// Whether coalescable events that are broadcast while the channel is backed up replace the earlier ones
+    /// of the same kind that have not been sent yet.
+    coalescing: bool,
+    /// Packets of coalescable events that wait for the channel's queue to drain, at most one per kind of event.
+    coalesced_packets: Vec<(Discriminant<EventMsg>, Packet)>,
+}
+
+impl<C: AsFd> AsFd for Client<C> {
     fn as_fd(&self) -> std::os::unix::prelude::BorrowedFd<'_> {
         self.channel.as_fd()
     }
 }
 
-impl AsRawFd for Client {
+impl<C: AsFd> AsRawFd for Client<C> {
     fn as_raw_fd(&self) -> std::os::unix::prelude::RawFd {
         self.as_fd().as_raw_fd()
     }
 }
 
-impl Client {
-    pub fn new(channel: StreamChannel) -> Self {
+impl<C: Channel> Client<C> {
+    pub fn new(channel: C, credentials: Credentials) -> Self {
         Self {
-            channel
+            channel,
+            state: ClientState::AwaitingHello,
+            name: None,
+            credentials,
+            write_interest: false,
+            connected_at: Instant::now(),
+            last_activity: Instant::now(),
+            last_ping: Instant::now(),
+            pending_ping: None,
+            missed_pongs: 0,
+            session_token: None,
+            client_list_requests: Vec::new(),
+            stats: ClientStats::default(),
+            subscriptions: HashSet::new(),
+            rate_limit: None,
+            deferred_packets: VecDeque::new(),
+            rate_violations: 0,
+            coalescing: false,
+            coalesced_packets: Vec::new(),
         }
     }
 
-    pub fn channel(&self) -> &StreamChannel {
+    pub fn channel(&self) -> &C {
         &self.channel
     }
 
-    pub fn channel_mut(&mut self) -> &mut StreamChannel {
+    pub fn channel_mut(&mut self) -> &mut C {
         &mut self.channel
     }
+
+    /// The credentials of the process on the other side of the channel, for making authorization decisions.
+    pub fn credentials(&self) -> Credentials {
+        self.credentials
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.credentials.pid
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.credentials.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.credentials.gid
+    }
+
+    /// Whether the channel has packets queued that are waiting for the socket to become writable, or there are
+    /// coalesced packets that still have to be handed to the channel.
+    pub fn wants_write(&self) -> bool {
+        self.channel.has_pending_writes() || !self.coalesced_packets.is_empty()
+    }
+
+    /// How many bytes are waiting for the socket to become writable.
+    pub fn queued_bytes(&self) -> usize {
+        self.channel.queued_bytes()
+    }
+
+    pub fn write_interest(&self) -> bool {
+        self.write_interest
+    }
+
+    pub fn set_write_interest(&mut self, write_interest: bool) {
+        self.write_interest = write_interest;
+    }
+
+    pub fn state(&self) -> ClientState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: ClientState) {
+        self.state = state;
+    }
+
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(ClientName::as_str)
+    }
+
+    pub fn set_name(&mut self, name: ClientName) {
+        self.name = Some(name);
+    }
+
+    pub fn session_token(&self) -> Option<u64> {
+        self.session_token
+    }
+
+    pub fn set_session_token(&mut self, token: Option<u64>) {
+        self.session_token = token;
+    }
+
+    /// Returns what is needed to resume the session of the client with the given id later, if it has a session.
+    pub fn into_session(self, id: ClientId) -> Option<(u64, SessionState)> {
+        let state = SessionState { client_id: id, name: self.name?, subscriptions: self.subscriptions };
+        Some((self.session_token?, state))
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Records that the client has just sent something.
+    pub fn mark_active(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub fn last_ping(&self) -> Instant {
+        self.last_ping
+    }
+
+    pub fn missed_pongs(&self) -> u32 {
+        self.missed_pongs
+    }
+
+    /// Records that a ping has just been sent. If the previous ping was never answered, it counts as missed.
+    pub fn record_ping(&mut self, nonce: u64) {
+        if self.pending_ping.is_some() {
+            self.missed_pongs += 1;
+        }
+        self.pending_ping = Some(nonce);
+        self.last_ping = Instant::now();
+    }
+
+    /// Records that the client answered a ping. Pongs for anything but the most recent ping are ignored.
+    pub fn record_pong(&mut self, nonce: u64) {
+        if self.pending_ping == Some(nonce) {
+            self.pending_ping = None;
+            self.missed_pongs = 0;
+        }
+    }
+
+    /// Records that the client asked for the client list in the request with the given serial.
+    pub fn request_client_list(&mut self, serial: u32) {
+        self.client_list_requests.push(serial);
+    }
+
+    /// Returns the serials of the client list requests that still have to be answered.
+    pub fn take_client_list_requests(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.client_list_requests)
+    }
+
+    /// Adds the topics to the ones that the client has subscribed to.
+    pub fn subscribe(&mut self, topics: impl IntoIterator<Item = String>) {
+        self.subscriptions.extend(topics);
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscriptions.contains(topic)
+    }
+
+    /// Replaces the client's rate limit. The client starts with a full burst.
+    pub fn set_rate_limit(&mut self, rate_limit: Option<RateLimit>) {
+        self.rate_limit = rate_limit.map(TokenBucket::new);
+    }
+
+    /// Queues packets that have been read, to be handled as the rate limit allows.
+    pub fn defer_packets(&mut self, packets: impl IntoIterator<Item = Packet>) {
+        self.deferred_packets.extend(packets);
+    }
+
+    /// Takes the next deferred packet, unless there is none or the client has exceeded its rate limit.
+    pub fn next_allowed_packet(&mut self) -> Option<Packet> {
+        if self.deferred_packets.is_empty() {
+            return None;
+        }
+        if let Some(bucket) = &mut self.rate_limit {
+            if !bucket.try_take() {
+                return None;
+            }
+        }
+        self.deferred_packets.pop_front()
+    }
+
+    /// Whether the client has packets waiting for its rate limit to allow them.
+    pub fn is_throttled(&self) -> bool {
+        !self.deferred_packets.is_empty()
+    }
+
+    pub fn rate_violations(&self) -> u32 {
+        self.rate_violations
+    }
+
+    /// Tops up the client's rate limit. Counts a violation if the client still had packets deferred, or
+    /// forgives all earlier violations if it did not.
+    pub fn refill_rate_limit(&mut self) {
+        if self.is_throttled() {
+            self.rate_violations += 1;
+        } else {
+            self.rate_violations = 0;
+        }
+        if let Some(bucket) = &mut self.rate_limit {
+            bucket.refill();
+        }
+    }
+
+    pub fn coalescing(&self) -> bool {
+        self.coalescing
+    }
+
+    pub fn set_coalescing(&mut self, coalescing: bool) {
+        self.coalescing = coalescing;
+    }
+
+    /// How many packets of coalescable events are waiting for the channel's queue to drain.
+    pub fn coalesced_count(&self) -> usize {
+        self.coalesced_packets.len()
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        self.stats
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.stats.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.stats.bytes_written
+    }
+
+    pub fn packets_read(&self) -> u64 {
+        self.stats.packets_read
+    }
+
+    pub fn packets_written(&self) -> u64 {
+        self.stats.packets_written
+    }
+
+    fn record_written(&mut self, packet: &Packet) {
+        self.stats.bytes_written += packet.len() as u64;
+        self.stats.packets_written += 1;
+    }
+}
+
+impl Client<StreamChannel> {
+    /// Queues a packet without trying to send it, counting it as written. Coalesced packets go first, so the
+    /// client receives everything in the order in which it was sent.
+    pub fn queue_packet(&mut self, packet: Packet) -> std::io::Result<()> {
+        self.queue_coalesced_packets()?;
+        self.record_written(&packet);
+        self.channel.queue_packets(vec![packet])
+    }
+
+    /// Sends a packet that is not a reply to any request. If the channel's queue has not drained yet, or
+    /// coalesced packets are still waiting, the packet gets queued as well, unless `kind` says which kind of coalescable event it holds and coalescing
+    /// is enabled. In that case, it replaces the packet of the same kind that is waiting for the queue to
+    /// drain, if any.
+    pub fn send_unsolicited(&mut self, packet: Packet, kind: Option<Discriminant<EventMsg>>) -> std::io::Result<()> {
+        if !self.wants_write() {
+            return self.write_packet(packet);
+        }
+        match kind {
+            Some(kind) if self.coalescing => {
+                match self.coalesced_packets.iter_mut().find(|(pending_kind, _)| *pending_kind == kind) {
+                    Some((_, pending)) => *pending = packet,
+                    None => self.coalesced_packets.push((kind, packet)),
+                }
+                Ok(())
+            },
+            _ => self.queue_packet(packet),
+        }
+    }
+
+    /// Sends as much of the channel's queue as the socket takes. Once the queue has drained, the coalesced
+    /// packets get sent as well.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.channel.flush()?;
+        if self.channel.has_pending_writes() || self.coalesced_packets.is_empty() {
+            return Ok(());
+        }
+        self.queue_coalesced_packets()?;
+        self.channel.flush()
+    }
+
+    fn queue_coalesced_packets(&mut self) -> std::io::Result<()> {
+        let packets: Vec<Packet> = self.coalesced_packets.drain(..).map(|(_, packet)| packet).collect();
+        for packet in &packets {
+            self.record_written(packet);
+        }
+        self.channel.queue_packets(packets)
+    }
+}
+
+/// Reading and writing through the client rather than its channel keeps the client's stats up to date.
+/// Written packets are counted when they are handed to the channel, whether or not they have been sent yet.
+impl<C: Channel> Channel for Client<C> {
+    fn read_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
+        let packets = self.channel.read_packets()?;
+        for packet in &packets {
+            self.stats.bytes_read += packet.len() as u64;
+            self.stats.packets_read += 1;
+        }
+        Ok(packets)
+    }
+
+    /// Coalesced packets that are still waiting get written first, so they do not get overtaken.
+    fn write_packet(&mut self, packet: Packet) -> Result<(), std::io::Error> {
+        for (_, pending) in std::mem::take(&mut self.coalesced_packets) {
+            self.record_written(&pending);
+            self.channel.write_packet(pending)?;
+        }
+        self.record_written(&packet);
+        self.channel.write_packet(packet)
+    }
+
+    fn queued_bytes(&self) -> usize {
+        self.channel.queued_bytes()
+    }
+}
+
+/// All connected clients. Clients are registered with the epoll when they are inserted and unregistered when
+/// they are removed, so the epoll never reports events for clients that are not in here.
+///
+/// Read-only access goes through the underlying Slab.
+pub struct Clients<C = StreamChannel> {
+    slab: Slab<Client<C>>,
+}
+
+impl<C> Default for Clients<C> {
+    fn default() -> Self {
+        Clients { slab: Slab::new() }
+    }
+}
+
+impl<C> Deref for Clients<C> {
+    type Target = Slab<Client<C>>;
+
+    fn deref(&self) -> &Slab<Client<C>> {
+        &self.slab
+    }
+}
+
+impl<C: Channel + AsFd> Clients<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the client and registers its channel with the epoll.
+    pub fn insert(&mut self, epoll: &Epoll<PollId>, client: Client<C>) -> std::io::Result<ClientId> {
+        let id = self.slab.insert(client);
+        if let Err(err) = epoll.add(&self.slab.get(id).unwrap().channel, PollId::Client(id)) {
+            self.slab.remove(id);
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    /// Removes the client and unregisters its channel from the epoll. Returns None if the id is stale.
+    pub fn remove(&mut self, epoll: &Epoll<PollId>, id: ClientId) -> Option<Client<C>> {
+        let client = self.slab.remove(id)?;
+        if let Err(err) = epoll.delete(&client.channel) {
+            warn!("Failed to remove a client from the epoll: {err}");
+        }
+        Some(client)
+    }
+
+    pub fn get_mut(&mut self, id: ClientId) -> Option<&mut Client<C>> {
+        self.slab.get_mut(id)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (ClientId, &mut Client<C>)> {
+        self.slab.iter_mut()
+    }
+
+    /// Finds the client that announced itself with the given name.
+    pub fn by_name(&self, name: &str) -> Option<(ClientId, &Client<C>)> {
+        self.slab.iter().find(|(_, client)| client.name() == Some(name))
+    }
+
+    /// Finds the client whose channel has the given file descriptor.
+    pub fn by_fd(&self, fd: RawFd) -> Option<(ClientId, &Client<C>)> {
+        self.slab.iter().find(|(_, client)| client.as_raw_fd() == fd)
+    }
+
+    /// Describes every connected client, in the form that gets sent to clients asking for the client list.
+    pub fn snapshot(&self) -> Vec<ClientInfo> {
+        self.slab.iter()
+            .map(|(id, client)| ClientInfo {
+                id: id.into(),
+                name: client.name().map(str::to_owned),
+                pid: client.pid(),
+                connected_for: client.connected_at().elapsed(),
+            })
+            .collect()
+    }
+
+    /// The traffic exchanged with every connected client.
+    pub fn stats(&self) -> Vec<(ClientId, ClientStats)> {
+        self.slab.iter().map(|(id, client)| (id, client.stats())).collect()
+    }
+}
+
+/// How many packets per second a client may send in the long run, and how many it may send at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub packets_per_second: u32,
+    pub burst: u32,
+}
+
+/// A token bucket: every packet takes a token, and tokens come back at the configured rate up to the burst.
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    /// Fractional, so that frequent refills do not lose the tokens that accumulate between them.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket { limit, tokens: limit.burst.into(), last_refill: Instant::now() }
+    }
+
+    /// Takes a token if there is one.
+    pub fn try_take(&mut self) -> bool {
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Adds the tokens that came back since the last refill.
+    pub fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(self.limit.packets_per_second)).min(self.limit.burst.into());
+        self.last_refill = now;
+    }
+}
+
+/// The sessions of clients that disconnected recently, so they can reconnect and resume where they left off.
+pub struct Sessions {
+    sessions: HashMap<u64, Session>,
+    /// How long the session of a disconnected client is kept.
+    grace_period: Duration,
+}
+
+struct Session {
+    state: SessionState,
+    expires_at: Instant,
+}
+
+/// What a client gets back when it resumes its session.
+pub struct SessionState {
+    /// The id that the client had before it disconnected. The resumed client gets a new one.
+    pub client_id: ClientId,
+    pub name: ClientName,
+    pub subscriptions: HashSet<String>,
+}
+
+impl Sessions {
+    pub fn new(grace_period: Duration) -> Self {
+        Sessions { sessions: HashMap::new(), grace_period }
+    }
+
+    pub fn set_grace_period(&mut self, grace_period: Duration) {
+        self.grace_period = grace_period;
+    }
+
+    /// Creates a token for a new session. Tokens are random, so clients cannot guess the tokens of others.
+    pub fn issue_token(&self) -> u64 {
+        let mut bytes = [0u8; 8];
+        loop {
+            let filled = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+            if filled == bytes.len() as isize {
+                break;
+            }
+            let err = std::io::Error::last_os_error();
+            assert!(err.kind() == std::io::ErrorKind::Interrupted, "Failed to generate a session token: {err}");
+        }
+        u64::from_ne_bytes(bytes)
+    }
+
+    /// Keeps the session of a client that disconnected for the grace period.
+    pub fn suspend(&mut self, token: u64, state: SessionState) {
+        self.sessions.insert(token, Session { state, expires_at: Instant::now() + self.grace_period });
+    }
+
+    /// Ends a suspended session and returns the state of its client, unless it does not exist or has expired.
+    /// Expired sessions are left for `remove_expired`.
+    pub fn resume(&mut self, token: u64) -> Option<SessionState> {
+        if self.sessions.get(&token)?.expires_at <= Instant::now() {
+            return None;
+        }
+        self.sessions.remove(&token).map(|session| session.state)
+    }
+
+    /// Forgets the sessions whose grace period is over. Returns the ids that their clients had, because
+    /// those clients are gone for good now.
+    pub fn remove_expired(&mut self) -> Vec<ClientId> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        self.sessions.retain(|_, session| {
+            let keep = session.expires_at > now;
+            if !keep {
+                expired.push(session.state.client_id);
+            }
+            keep
+        });
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+/// Identifies an entry of a Slab. The generation makes sure that the id of a removed entry does not refer to a
+/// new entry that happens to reuse the same slot, for example when the epoll still reports events for a client
+/// that has been removed while handling an earlier event of the same poll.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ClientId {
+    pub index: u32,
+    pub generation: u16,
+}
+
+impl From<ClientId> for u64 {
+    fn from(id: ClientId) -> u64 {
+        (u64::from(id.generation) << 32) | u64::from(id.index)
+    }
+}
+
+/// Stores values in a Vec and hands out ids for them, so they can be looked up without hashing.
+/// Slots of removed values get reused by later insertions.
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    /// Indices of the slots that currently hold no value.
+    free: Vec<u32>,
+    len: usize,
+}
+
+struct Slot<T> {
+    generation: u16,
+    value: Option<T>,
 }
 
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Slab { slots: Vec::new(), free: Vec::new(), len: 0 }
+    }
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, value: T) -> ClientId {
+        self.len += 1;
+        if let Some(index) = self.free.pop() {
+            let slot = &mut self.slots[index as usize];
+            slot.value = Some(value);
+            return ClientId { index, generation: slot.generation };
+        }
+
+        let index = self.slots.len().try_into().expect("Too many entries in the slab!");
+        self.slots.push(Slot { generation: 0, value: Some(value) });
+        ClientId { index, generation: 0 }
+    }
+
+    /// Removes and returns the value with the given id. Returns None if the id is stale.
+    pub fn remove(&mut self, id: ClientId) -> Option<T> {
+        let slot = self.slots.get_mut(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn get(&self, id: ClientId) -> Option<&T> {
+        let slot = self.slots.get(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: ClientId) -> Option<&mut T> {
+        let slot = self.slots.get_mut(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+        slot.value.as_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ClientId, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = ClientId { index: index as u32, generation: slot.generation };
+            slot.value.as_ref().map(|value| (id, value))
+        })
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (ClientId, &mut T)> {
+        self.slots.iter_mut().enumerate().filter_map(|(index, slot)| {
+            let id = ClientId { index: index as u32, generation: slot.generation };
+            slot.value.as_mut().map(|value| (id, value))
+        })
+    }
+
+    pub fn ids(&self) -> Vec<ClientId> {
+        self.iter().map(|(id, _)| id).collect()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use libuio::message::{Event, HelloMsg, RequestMsg};
+
+    use super::*;
+    use crate::epoll::Message;
+    use crate::test_utils::{connected_client, send_request};
+
+    fn stats_packet(bytes_read: u64) -> Packet {
+        let stats = ClientStats { bytes_read, ..ClientStats::default() };
+        Packet::try_from((Event { serial: None, msg: EventMsg::Stats { stats } }, Vec::new())).unwrap()
+    }
+
+    #[test]
+    fn coalesced_packets_are_sent_before_a_reply_written_after_the_queue_drained() {
+        let (mut channel, mut client) = connected_client();
+        client.set_coalescing(true);
+        client.channel_mut().write_packet(Packet::new(vec![0; 1024 * 1024], Vec::new())).unwrap();
+        let kind = std::mem::discriminant(&EventMsg::Stats { stats: ClientStats::default() });
+        client.send_unsolicited(stats_packet(1), Some(kind)).unwrap();
+        assert_eq!(client.coalesced_count(), 1);
+
+        // Let the channel's queue drain without going through the client, like a reply flushing it would.
+        let mut received = Vec::new();
+        while client.channel().has_pending_writes() {
+            received.extend(channel.read_packets().unwrap());
+            client.channel_mut().flush().unwrap();
+        }
+        assert!(client.wants_write());
+
+        client.write_packet(stats_packet(2)).unwrap();
+        while received.len() < 3 {
+            received.extend(channel.read_packets().unwrap());
+            client.flush().unwrap();
+        }
+        let bytes_read: Vec<u64> = received.drain(1 ..)
+            .map(|packet| match <(Event, _)>::try_from(packet).unwrap().0.msg {
+                EventMsg::Stats { stats } => stats.bytes_read,
+                msg => panic!("Got {msg:?}"),
+            })
+            .collect();
+        assert_eq!(bytes_read, vec![1, 2]);
+        assert!(!client.wants_write());
+    }
+
+    #[test]
+    fn clients_keep_the_epoll_in_sync() {
+        let epoll = Epoll::new().unwrap();
+        let mut clients = Clients::new();
+        let (mut channel, client) = connected_client();
+        let id = clients.insert(&epoll, client).unwrap();
+
+        send_request(&mut channel, 0, RequestMsg::Hello(HelloMsg::current()));
+        let events = epoll.poll_timeout(Some(Duration::ZERO)).unwrap();
+        assert!(matches!(events[..], [Message::Ready(key)] if key == PollId::Client(id)));
+
+        assert!(clients.remove(&epoll, id).is_some());
+        assert!(clients.is_empty());
+        assert!(clients.remove(&epoll, id).is_none());
+        assert!(epoll.poll_timeout(Some(Duration::ZERO)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn client_names_are_validated() {
+        assert_eq!(ClientName::new("Terminal".to_owned(), 8).unwrap().as_str(), "Terminal");
+        assert!(ClientName::new("Terminal".to_owned(), 7).is_err());
+        assert!(ClientName::new("Line\nbreak".to_owned(), 64).is_err());
+        assert!(ClientName::new("\x1b[31mRed".to_owned(), 64).is_err());
+        // Multibyte characters count with their length in bytes.
+        assert!(ClientName::new("ü".repeat(4), 7).is_err());
+    }
+
+    fn session_state(name: &str) -> SessionState {
+        let client_id = ClientId { index: 0, generation: 0 };
+        SessionState { client_id, name: ClientName::new(name.to_owned(), 16).unwrap(), subscriptions: HashSet::new() }
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let mut sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.issue_token();
+        sessions.suspend(token, session_state("Kept"));
+        assert!(sessions.resume(token.wrapping_add(1)).is_none());
+        assert_eq!(sessions.resume(token).unwrap().name.as_str(), "Kept");
+        // A session can only be resumed once.
+        assert!(sessions.resume(token).is_none());
+
+        sessions.set_grace_period(Duration::ZERO);
+        sessions.suspend(token, session_state("Expired"));
+        assert!(sessions.resume(token).is_none());
+        sessions.suspend(token, session_state("Expired"));
+        assert_eq!(sessions.remove_expired(), vec![ClientId { index: 0, generation: 0 }]);
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn clients_can_be_found_by_name() {
+        let epoll = Epoll::new().unwrap();
+        let mut clients = Clients::new();
+        let (_first_channel, first) = connected_client();
+        let (_second_channel, mut second) = connected_client();
+        second.set_name(ClientName::new("Second".to_owned(), 16).unwrap());
+        clients.insert(&epoll, first).unwrap();
+        let second_id = clients.insert(&epoll, second).unwrap();
+
+        assert_eq!(clients.by_name("Second").map(|(id, _)| id), Some(second_id));
+        assert!(clients.by_name("Third").is_none());
+    }
+
+    #[test]
+    fn slab_reuses_slots_with_new_generation() {
+        let mut slab = Slab::new();
+        let first = slab.insert("first");
+        let second = slab.insert("second");
+        assert_eq!(slab.remove(first), Some("first"));
+
+        let third = slab.insert("third");
+        assert_eq!(third.index, first.index);
+        assert_ne!(third.generation, first.generation);
+
+        assert_eq!(slab.get(first), None);
+        assert_eq!(slab.remove(first), None);
+        assert_eq!(slab.get(third), Some(&"third"));
+        assert_eq!(slab.get(second), Some(&"second"));
+        assert_eq!(slab.len(), 2);
+    }
+}
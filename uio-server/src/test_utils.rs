@@ -0,0 +1,94 @@
+//! Helpers shared by the unit tests of the server.
+
+use std::collections::VecDeque;
+use std::os::fd::OwnedFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libuio::channel::Channel;
+use libuio::message::{Event, Request, RequestMsg};
+use libuio::shm::SharedBuffer;
+use libuio::socket::{Credentials, Packet, StreamChannel, StreamSocket};
+
+use crate::state::Client;
+
+/// Returns a path for a socket that no other test is using.
+pub fn unique_socket_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("uio-test-{}-{id}.socket", std::process::id()))
+}
+
+/// Creates a connected pair of a client-side channel and the server-side Client belonging to it.
+pub fn connected_client() -> (StreamChannel, Client) {
+    let path = unique_socket_path();
+    let socket = StreamSocket::open(path.clone()).expect("Failed to open a test socket.");
+    let channel = StreamChannel::open(&path).expect("Failed to connect to the test socket.");
+    let (accepted, credentials) = socket.accept().expect("Failed to accept the test connection.");
+    let client = Client::new(accepted, credentials);
+    (channel, client)
+}
+
+pub fn send_request(channel: &mut StreamChannel, serial: u32, msg: RequestMsg) {
+    channel.send_request(Request { serial, msg }, Vec::new()).unwrap();
+}
+
+/// Returns all events that are currently available on the channel.
+pub fn receive_events(channel: &mut StreamChannel) -> Vec<Event> {
+    channel.recv_events().unwrap().into_iter()
+        .map(|message| message.unwrap().msg)
+        .collect()
+}
+
+/// An in-memory channel. Packets pushed into `incoming` get read by the server, and packets that the server
+/// writes end up in `outgoing`.
+#[derive(Default)]
+pub struct MockChannel {
+    pub incoming: VecDeque<Packet>,
+    pub outgoing: VecDeque<Packet>,
+}
+
+impl MockChannel {
+    pub fn push_request(&mut self, serial: u32, msg: RequestMsg) {
+        self.push_request_with_fds(serial, msg, Vec::new());
+    }
+
+    pub fn push_request_with_fds(&mut self, serial: u32, msg: RequestMsg, fds: Vec<OwnedFd>) {
+        let packet = Packet::try_from((Request { serial, msg }, fds)).unwrap();
+        self.incoming.push_back(packet);
+    }
+
+    /// Pushes a `ShareBuffer` request with a properly sealed buffer attached.
+    pub fn push_shared_buffer(&mut self, serial: u32) {
+        let (msg, fds) = SharedBuffer::new("test", b"test").unwrap().into_request();
+        self.push_request_with_fds(serial, msg, fds);
+    }
+
+    /// Removes and returns all events that have been written to this channel.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        self.outgoing.drain(..)
+            .map(|packet| <(Event, _)>::try_from(packet).unwrap().0)
+            .collect()
+    }
+}
+
+impl Channel for MockChannel {
+    fn read_packets(&mut self) -> Result<Vec<Packet>, std::io::Error> {
+        Ok(self.incoming.drain(..).collect())
+    }
+
+    fn write_packet(&mut self, packet: Packet) -> Result<(), std::io::Error> {
+        self.outgoing.push_back(packet);
+        Ok(())
+    }
+
+    fn queued_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Creates a client that is connected through a MockChannel.
+pub fn mock_client() -> Client<MockChannel> {
+    let credentials = Credentials { pid: 0, uid: 0, gid: 0 };
+    Client::new(MockChannel::default(), credentials)
+}
@@ -0,0 +1,59 @@
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::time::Duration;
+
+use rustix::time::{Itimerspec, TimerfdClockId, TimerfdFlags, TimerfdTimerFlags, Timespec};
+
+/// A timerfd that becomes readable at a fixed interval, so it can wake up an epoll.
+pub struct Timer {
+    fd: OwnedFd,
+}
+
+impl Timer {
+    /// Creates a timer that fires every `interval`, starting one interval from now. `None` creates a timer
+    /// that never fires until an interval is set.
+    pub fn new(interval: Option<Duration>) -> std::io::Result<Timer> {
+        let fd = rustix::time::timerfd_create(
+            TimerfdClockId::Monotonic,
+            TimerfdFlags::NONBLOCK | TimerfdFlags::CLOEXEC,
+        )?;
+        let timer = Timer { fd };
+        timer.set_interval(interval)?;
+        Ok(timer)
+    }
+
+    /// Makes the timer fire every `interval` from now on, or stops it if `interval` is `None`. A zero interval
+    /// would disarm the timer, so it is rounded up to a nanosecond.
+    pub fn set_interval(&self, interval: Option<Duration>) -> std::io::Result<()> {
+        let interval = match interval {
+            Some(interval) => Self::timespec(interval.max(Duration::from_nanos(1))),
+            None => Self::timespec(Duration::ZERO),
+        };
+        let spec = Itimerspec { it_interval: interval, it_value: interval };
+        rustix::time::timerfd_settime(&self.fd, TimerfdTimerFlags::empty(), &spec)?;
+        Ok(())
+    }
+
+    /// Returns how often the timer has fired since the last call, which may be zero. Reading resets the
+    /// count, so the timer stops being readable until it fires again.
+    pub fn take_expirations(&self) -> std::io::Result<u64> {
+        let mut buffer = [0; 8];
+        match rustix::io::read(&self.fd, &mut buffer) {
+            Ok(_) => Ok(u64::from_ne_bytes(buffer)),
+            Err(rustix::io::Errno::AGAIN) => Ok(0),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn timespec(duration: Duration) -> Timespec {
+        Timespec {
+            tv_sec: duration.as_secs().try_into().unwrap_or(i64::MAX),
+            tv_nsec: duration.subsec_nanos().into(),
+        }
+    }
+}
+
+impl AsFd for Timer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
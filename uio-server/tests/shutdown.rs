@@ -0,0 +1,34 @@
+//! Runs the actual server binary to check that it shuts down cleanly when asked to.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libuio::client::{connect_with_retry, RetryPolicy, UioClient};
+use libuio::message::EventMsg;
+
+/// Returns a path for a socket that no other test is using, like the helper of the unit tests.
+fn unique_socket_path() -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("uio-shutdown-test-{}-{id}.socket", std::process::id()))
+}
+
+#[test]
+fn sigterm_removes_socket_and_notifies_clients() {
+    let path = unique_socket_path();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_uio-server"))
+        .env("UIO_SOCKET", &path)
+        .spawn()
+        .unwrap();
+
+    let policy = RetryPolicy { max_attempts: Some(100), ..RetryPolicy::default() };
+    let mut client = UioClient::with_channel(connect_with_retry(&path, &policy).unwrap()).unwrap();
+    client.announce("Shutdown test").unwrap();
+
+    rustix::process::kill_process(rustix::process::Pid::from_child(&server), rustix::process::Signal::Term).unwrap();
+
+    assert!(matches!(client.recv_event().unwrap().msg, EventMsg::Shutdown));
+    assert!(server.wait().unwrap().success());
+    assert!(!path.exists());
+}